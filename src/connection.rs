@@ -1,17 +1,25 @@
+use crate::dedupe::{self, Observation};
+use crate::envelope::Envelope;
 use crate::errors;
+use crate::framing::FrameReader;
 use crate::kafka::KafkaMessage;
 use crate::merge;
+use crate::multiline::{Aggregator, Complete};
+use crate::output::{OutputMessage, Outputs};
 use crate::parse;
+use crate::pointer;
+use crate::reload::ReloadableSettings;
 use crate::rules;
 use crate::settings::*;
 use crate::status::{Statistic, Stats};
 /**
  * The connection module is responsible for handling everything pertaining to a single inbound TCP
- * connection.
+ * connection, or the stream of datagrams received on a UDP socket.
  */
 use async_std::{
+    future,
     io::BufReader,
-    prelude::*,
+    net::{SocketAddr, UdpSocket},
     sync::{Arc, Sender},
     task,
 };
@@ -19,335 +27,974 @@ use chrono::prelude::*;
 use handlebars::Handlebars;
 use log::*;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/**
+ * How long to wait before receiving again after the socket returned an error, so that an error
+ * which persists doesn't spin the listener
+ */
+const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 
 /**
  * RuleState exists to help carry state into merge/replacement functions and exists only during the
  * processing of rules
  */
 struct RuleState<'a> {
-    variables: &'a HashMap<String, String>,
-    hb: &'a handlebars::Handlebars<'a>,
+    variables: &'a rules::Variables,
+    hb: &'a Handlebars<'a>,
     stats: Sender<Statistic>,
 }
 
-/**
- * Simple type to capture a map of precompiled jmespath expressions
- */
-pub type JmesPathExpressions<'a> = HashMap<String, jmespath::Expression<'a>>;
-
 pub struct Connection {
     /**
-     * A reference to the global Settings object for all configuration information
+     * A reference to the reloadable Settings object for all configuration information
      */
-    settings: Arc<Settings>,
+    settings: Arc<ReloadableSettings>,
     /**
-     * The sender-side of the channel to our Kafka connection, allowing the logs read in to be
-     * sent over to the Kafka handler
+     * The outputs which rules can send messages to, including the default Kafka producer used
+     * by the forward action
      */
-    sender: Sender<KafkaMessage>,
+    outputs: Arc<Outputs>,
     stats: Sender<Statistic>,
+    /**
+     * The framing configured for the listener which accepted this connection
+     */
+    framing: Framing,
+    /**
+     * The largest frame accepted from a stream, in bytes
+     */
+    max_frame_size: usize,
+    /**
+     * The multi-line aggregation configured for the listener, if any
+     */
+    multiline: Option<Multiline>,
 }
 
 impl Connection {
     pub fn new(
-        settings: Arc<Settings>,
-        sender: Sender<KafkaMessage>,
+        settings: Arc<ReloadableSettings>,
+        outputs: Arc<Outputs>,
         stats: Sender<Statistic>,
+        framing: Framing,
+        max_frame_size: usize,
+        multiline: Option<Multiline>,
     ) -> Self {
         Connection {
             settings,
-            sender,
+            outputs,
             stats,
+            framing,
+            max_frame_size,
+            multiline,
         }
     }
 
     /**
      * connection_loop is responsible for handling incoming syslog streams connections
      *
+     * The peer is the address of the sender, if it is known
      */
     pub async fn read_logs<R: async_std::io::Read + std::marker::Unpin>(
         &self,
         reader: BufReader<R>,
+        peer: Option<SocketAddr>,
     ) -> Result<(), errors::HotdogError> {
-        let mut lines = reader.lines();
+        let mut frames = FrameReader::new(reader, self.framing, self.max_frame_size);
+        let mut aggregator = self.multiline.as_ref().map(Aggregator::new);
 
-        let mut hb = Handlebars::new();
-        let mut jmespaths = JmesPathExpressions::new();
+        loop {
+            /*
+             * Pending multi-line messages must be processed once they time out, even if nothing
+             * else arrives on the connection
+             */
+            if let Some(deadline) = aggregator.as_ref().and_then(|a| a.next_deadline()) {
+                let now = Instant::now();
+                if deadline <= now
+                    || future::timeout(deadline - now, frames.ready())
+                        .await
+                        .is_err()
+                {
+                    self.flush_expired(&mut aggregator).await;
+                    continue;
+                }
+            }
 
-        if !precompile_templates(&mut hb, self.settings.clone()) {
-            error!("Failing to precompile templates is a fatal error, not going to parse logs since the configuration is broken");
-            // TODO fix the Err types
-            return Ok(());
+            match frames.next_frame().await {
+                Ok(Some(line)) => self.receive(line, peer, &mut aggregator).await,
+                Ok(None) => break,
+                Err(e) => {
+                    self.flush(&mut aggregator).await;
+                    return Err(e.into());
+                }
+            }
         }
 
-        if !precompile_jmespath(&mut jmespaths, self.settings.clone()) {
-            error!("Failing to precompile jmespaths is a fata error, not parsing this connection's logs because the configuration is broken");
-            // TODO fix the Err types
-            return Ok(());
+        self.flush(&mut aggregator).await;
+        Ok(())
+    }
+
+    /**
+     * read_datagrams is responsible for handling syslog messages received over a UDP socket.
+     *
+     * Datagrams larger than max_size will be truncated before they are processed
+     */
+    pub async fn read_datagrams(
+        &self,
+        socket: UdpSocket,
+        max_size: usize,
+    ) -> Result<(), errors::HotdogError> {
+        /*
+         * The extra byte allows for detecting datagrams which were larger than max_size, since
+         * recv_from() will silently discard whatever doesn't fit into the buffer
+         */
+        let mut buffer = vec![0; max_size + 1];
+        let mut aggregator = self.multiline.as_ref().map(Aggregator::new);
+
+        loop {
+            /*
+             * Receiving a datagram can be safely abandoned, so the pending multi-line messages
+             * are processed if nothing arrives before they time out
+             */
+            let received = match aggregator.as_ref().and_then(|a| a.next_deadline()) {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    let received = if deadline > now {
+                        future::timeout(deadline - now, socket.recv_from(&mut buffer))
+                            .await
+                            .ok()
+                    } else {
+                        None
+                    };
+
+                    match received {
+                        Some(received) => received,
+                        None => {
+                            self.flush_expired(&mut aggregator).await;
+                            continue;
+                        }
+                    }
+                }
+                None => socket.recv_from(&mut buffer).await,
+            };
+
+            let (mut len, peer) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Failed to receive a datagram: {:?}", e);
+                    task::sleep(RECV_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            debug!("Received {} bytes from: {}", len, peer);
+            self.stats.send((Stats::DatagramReceived, 1)).await;
+
+            if len > max_size {
+                warn!("Truncating a datagram from {} to {} bytes", peer, max_size);
+                self.stats.send((Stats::DatagramTruncated, 1)).await;
+                len = max_size;
+            }
+
+            /*
+             * Senders will frequently terminate their datagrams with newlines or NULs, which
+             * shouldn't be considered part of the message
+             */
+            let line = String::from_utf8_lossy(&buffer[..len])
+                .trim_end_matches(|c| c == '\n' || c == '\r' || c == '\0')
+                .to_string();
+            self.receive(line, Some(peer), &mut aggregator).await;
         }
+    }
 
-        while let Some(line) = lines.next().await {
-            let line = line?;
-            debug!("log: {}", line);
+    /**
+     * Process a line received from the peer, first joining it with the other lines of its message
+     * when multi-line aggregation is configured
+     */
+    async fn receive(
+        &self,
+        line: String,
+        peer: Option<SocketAddr>,
+        aggregator: &mut Option<Aggregator>,
+    ) {
+        match aggregator {
+            Some(aggregator) => {
+                if let Some(msg) = self.parse(line).await {
+                    let complete = aggregator.push(msg, peer, Instant::now());
+                    self.process_complete(complete).await;
+                }
+            }
+            None => self.process_line(line, peer).await,
+        }
+    }
 
-            let parsed = parse::parse_line(line);
+    /**
+     * Process the multi-line messages which have timed out
+     */
+    async fn flush_expired(&self, aggregator: &mut Option<Aggregator>) {
+        if let Some(aggregator) = aggregator {
+            let complete = aggregator.flush_expired(Instant::now());
+            self.process_complete(complete).await;
+        }
+    }
+
+    /**
+     * Process every pending multi-line message, such as when the connection is closing
+     */
+    async fn flush(&self, aggregator: &mut Option<Aggregator>) {
+        if let Some(aggregator) = aggregator {
+            let complete = aggregator.flush();
+            self.process_complete(complete).await;
+        }
+    }
+
+    async fn process_complete(&self, complete: Vec<Complete>) {
+        for (msg, peer) in complete {
+            self.process_message(msg, peer).await;
+        }
+    }
 
-            if let Err(e) = &parsed {
+    /**
+     * Parse a single syslog line and run it through the configured rules
+     */
+    async fn process_line(&self, line: String, peer: Option<SocketAddr>) {
+        if let Some(msg) = self.parse(line).await {
+            self.process_message(msg, peer).await;
+        }
+    }
+
+    /**
+     * Parse a single syslog line, returning None if it could not be parsed
+     */
+    async fn parse(&self, line: String) -> Option<parse::SyslogMessage> {
+        debug!("log: {}", line);
+
+        match parse::parse_line(line) {
+            Ok(msg) => {
+                self.stats.send((Stats::LineReceived, 1)).await;
+                Some(msg)
+            }
+            Err(e) => {
                 self.stats.send((Stats::LogParseError, 1)).await;
                 error!("failed to parse message: {:?}", e);
-                continue;
+                None
+            }
+        }
+    }
+
+    /**
+     * Run the parsed message through the configured rules
+     */
+    async fn process_message(&self, msg: parse::SyslogMessage, peer: Option<SocketAddr>) {
+        /*
+         * Fetching the engine for every message ensures that reloaded rules are picked up by
+         * existing connections
+         */
+        let engine = self.settings.current();
+        let hb = &engine.hb;
+        let jmespaths = &engine.jmespaths;
+
+        let received_at = Utc::now();
+        let mut continue_rules = true;
+        debug!("parsed as: {}", msg.msg);
+
+        /*
+         * Parse the message as JSON at most once, no matter how many rules want it
+         */
+        let json = if engine.settings.rules.iter().any(|rule| rule.parse_json) {
+            serde_json::from_str::<serde_json::Value>(&msg.msg).ok()
+        } else {
+            None
+        };
+
+        for (rule_index, rule) in engine.settings.rules.iter().enumerate() {
+            /*
+             * If we have been told to stop processing rules, then it's time to bail on this log
+             * message
+             */
+            if !continue_rules {
+                break;
+            }
+
+            // The output buffer that we will ultimately send along to the Kafka service
+            let mut output = String::new();
+            let mut hash = variables_for(&msg);
+
+            if rule.parse_json {
+                if let Some(json) = &json {
+                    hash.insert("json".to_string(), json.clone());
+                }
             }
+
+            let rule_matches = rules::apply_rule(&rule, &msg, jmespaths, &mut hash);
+
             /*
-             * Now that we've logged the error, let's unpack and bubble the error anyways
+             * This specific didn't match, so onto the next one
              */
-            let msg = parsed.unwrap();
-            self.stats.send((Stats::LineReceived, 1)).await;
-            let mut continue_rules = true;
-            debug!("parsed as: {}", msg.msg);
+            if !rule_matches {
+                continue;
+            }
+
+            let rule_state = RuleState {
+                hb,
+                variables: &hash,
+                stats: self.stats.clone(),
+            };
 
-            for rule in self.settings.rules.iter() {
+            /*
+             * Process the actions one the rule has matched
+             */
+            for index in 0..rule.actions.len() {
+                let action = &rule.actions[index];
                 /*
-                 * If we have been told to stop processing rules, then it's time to bail on this log
-                 * message
+                 * @stjepang says this will fix slow future polling
+                 *
+                 * The underlying problem here is that this _can_ be a very tight
+                 * and CPU-bound loop under heavy load conditions. There is nothing
+                 * inherent in smol (under async-std 1.6.x) which will properly
+                 * yield to other tasks in the runtime.
                  */
-                if !continue_rules {
-                    break;
-                }
+                task::yield_now().await;
 
-                // The output buffer that we will ultimately send along to the Kafka service
-                let mut output = String::new();
-                let mut rule_matches = false;
-                let mut hash = HashMap::new();
-                hash.insert("msg".to_string(), String::from(&msg.msg));
-                hash.insert("version".to_string(), env!["CARGO_PKG_VERSION"].to_string());
-                hash.insert("iso8601".to_string(), Utc::now().to_rfc3339());
-
-                match rule.field {
-                    Field::Msg => {
-                        rule_matches = rules::apply_rule(&rule, &msg.msg, &jmespaths, &mut hash);
-                    }
-                    Field::Appname => {
-                        if let Some(appname) = &msg.appname {
-                            rule_matches =
-                                rules::apply_rule(&rule, &appname, &jmespaths, &mut hash);
+                match action {
+                    Action::Forward {
+                        topic,
+                        key,
+                        headers,
+                        partition,
+                        partitioner,
+                    } => {
+                        /*
+                         * If a custom output was never defined, just take the
+                         * raw message and pass that along.
+                         */
+                        if output.is_empty() {
+                            output = String::from(&msg.msg);
+                        }
+                        let template_id = rules::template_id_for(&rule, index);
+                        let field = |name: &str| rules::field_template_id(&template_id, name);
+
+                        if let Ok(actual_topic) = hb.render(&field("topic"), &hash) {
+                            debug!("Enqueueing for topic: `{}`", actual_topic);
+                            /*
+                             * `output` is consumed by send_to_kafka, so the rest of the rules
+                             * should be skipped.
+                             */
+                            let mut kmsg = KafkaMessage::new(actual_topic, output);
+
+                            /*
+                             * A key or header which cannot be rendered shouldn't cause the
+                             * message to be lost, so it is forwarded without them
+                             */
+                            if let Some(key) = key {
+                                if let Ok(actual_key) = hb.render(&field("key"), &hash) {
+                                    kmsg.set_key(actual_key);
+                                } else {
+                                    error!("Failed to process the configured key: `{}`", key);
+                                    self.stats.send((Stats::KeyParseFailed, 1)).await;
+                                }
+                            }
+
+                            for name in headers.keys() {
+                                let id = field(&format!("headers/{}", name));
+                                if let Ok(actual_value) = hb.render(&id, &hash) {
+                                    kmsg.add_header(name.to_string(), actual_value);
+                                } else {
+                                    error!("Failed to process the configured header: `{}`", name);
+                                    self.stats.send((Stats::HeaderParseFailed, 1)).await;
+                                }
+                            }
+
+                            if let Some(partition) = partition {
+                                match hb
+                                    .render(&field("partition"), &hash)
+                                    .map(|rendered| rendered.trim().parse::<i32>())
+                                {
+                                    Ok(Ok(actual_partition)) => {
+                                        kmsg.set_partition(actual_partition)
+                                    }
+                                    _ => {
+                                        error!(
+                                            "Failed to process the configured partition: `{}`",
+                                            partition
+                                        );
+                                        self.stats.send((Stats::PartitionParseFailed, 1)).await;
+                                    }
+                                }
+                            }
+                            if let Some(partitioner) = partitioner {
+                                kmsg.set_partitioner(*partitioner);
+                            }
+
+                            self.outputs.forward(kmsg).await;
+                            /*
+                             * Ensure that we're allowing other tasks to execute when we pass
+                             * things off to the channel
+                             *
+                             * See also https://github.com/stjepang/smol/issues/159
+                             */
+                            task::yield_now().await;
+                            continue_rules = false;
+                        } else {
+                            error!("Failed to process the configured topic: `{}`", topic);
+                            self.stats.send((Stats::TopicParseFailed, 1)).await;
                         }
+                        break;
                     }
-                    Field::Hostname => {
-                        if let Some(hostname) = &msg.hostname {
-                            rule_matches =
-                                rules::apply_rule(&rule, &hostname, &jmespaths, &mut hash);
+
+                    Action::Envelope => {
+                        let body = if output.is_empty() { &msg.msg } else { &output };
+                        let envelope = Envelope::new(&msg, body, received_at, peer);
+
+                        match envelope.to_json() {
+                            Ok(json) => output = json,
+                            Err(e) => {
+                                error!("Failed to serialize the envelope: {:?}", e);
+                            }
                         }
                     }
-                    Field::Severity => {
-                        if let Some(severity) = &msg.severity {
-                            rule_matches =
-                                rules::apply_rule(&rule, &severity, &jmespaths, &mut hash);
+
+                    Action::Dedupe { key, topic, .. } => {
+                        let template_id = rules::template_id_for(&rule, index);
+                        let buffer = if output.is_empty() { &msg.msg } else { &output };
+
+                        /*
+                         * Without a topic for the summary the window could never be closed, so
+                         * the message is let through without being deduplicated
+                         */
+                        let field = |name: &str| rules::field_template_id(&template_id, name);
+                        let actual_topic = match hb.render(&field("topic"), &hash) {
+                            Ok(actual_topic) => actual_topic,
+                            Err(_) => {
+                                error!("Failed to process the configured topic: `{}`", topic);
+                                self.stats.send((Stats::TopicParseFailed, 1)).await;
+                                continue;
+                            }
+                        };
+
+                        let key = match key {
+                            Some(key) => hb.render(&field("key"), &hash).unwrap_or_else(|_| {
+                                error!("Failed to process the configured key: `{}`", key);
+                                buffer.to_string()
+                            }),
+                            None => buffer.to_string(),
+                        };
+
+                        let deduplicator = &engine.deduplicators[&template_id];
+                        match deduplicator.observe(&key, &actual_topic, &hash, Instant::now()) {
+                            Observation::Repeated => {
+                                debug!("Suppressing the repeated message");
+                                self.stats
+                                    .send((
+                                        Stats::Deduplicated {
+                                            rule: rules::name_for(&rule, rule_index),
+                                        },
+                                        1,
+                                    ))
+                                    .await;
+                                continue_rules = false;
+                                break;
+                            }
+                            /*
+                             * The first message of the window carries on, and the summary of
+                             * those suppressed is sent once the window closes
+                             */
+                            Observation::Opened(Some(evicted)) => {
+                                dedupe::summarize(&engine, &self.outputs, &template_id, evicted)
+                                    .await;
+                            }
+                            Observation::Opened(None) => {}
                         }
                     }
-                    Field::Facility => {
-                        if let Some(facility) = &msg.facility {
-                            rule_matches =
-                                rules::apply_rule(&rule, &facility, &jmespaths, &mut hash);
+
+                    Action::Drop => {
+                        debug!("Dropping the message");
+                        self.stats
+                            .send((
+                                Stats::Dropped {
+                                    rule: rules::name_for(&rule, rule_index),
+                                },
+                                1,
+                            ))
+                            .await;
+                        continue_rules = false;
+                        break;
+                    }
+
+                    Action::Merge {
+                        json,
+                        typed,
+                        coerce,
+                        path,
+                        ..
+                    } => {
+                        debug!("merging JSON content: {}", json);
+                        /*
+                         * Merging into the output buffer allows the results of earlier actions,
+                         * such as the envelope, to be merged with
+                         */
+                        let buffer = if output.is_empty() { &msg.msg } else { &output };
+                        let template_id = rules::template_id_for(&rule, index);
+                        let path = path.as_deref();
+                        let merged = if *typed {
+                            perform_typed_merge(
+                                buffer,
+                                &template_id,
+                                json,
+                                coerce,
+                                path,
+                                &rule_state,
+                            )
+                        } else {
+                            perform_merge(buffer, &template_id, path, &rule_state)
+                        };
+
+                        if let Ok(buffer) = merged {
+                            output = buffer;
+                        } else {
+                            continue_rules = false;
                         }
                     }
-                }
 
-                /*
-                 * This specific didn't match, so onto the next one
-                 */
-                if !rule_matches {
-                    continue;
-                }
+                    Action::Remove { .. } | Action::Rename { .. } | Action::Set { .. } => {
+                        let buffer = if output.is_empty() { &msg.msg } else { &output };
 
-                let rule_state = RuleState {
-                    hb: &hb,
-                    variables: &hash,
-                    stats: self.stats.clone(),
-                };
+                        let modified = match action {
+                            Action::Remove { path } => modify_json(buffer, |json| {
+                                pointer::take(json, path);
+                                Ok(())
+                            }),
+                            Action::Rename { from, to } => modify_json(buffer, |json| {
+                                if let Some(value) = pointer::take(json, from) {
+                                    if !pointer::set(json, to, value) {
+                                        return Err(format!("Unable to set {}", to));
+                                    }
+                                }
+                                Ok(())
+                            }),
+                            Action::Set {
+                                path,
+                                value,
+                                coerce,
+                            } => perform_set(
+                                buffer,
+                                &rules::template_id_for(&rule, index),
+                                path,
+                                value,
+                                *coerce,
+                                &rule_state,
+                            ),
+                            _ => Ok(buffer.to_string()),
+                        };
 
-                /*
-                 * Process the actions one the rule has matched
-                 */
-                for index in 0..rule.actions.len() {
-                    let action = &rule.actions[index];
-                    /*
-                     * @stjepang says this will fix slow future polling
-                     *
-                     * The underlying problem here is that this _can_ be a very tight
-                     * and CPU-bound loop under heavy load conditions. There is nothing
-                     * inherent in smol (under async-std 1.6.x) which will properly
-                     * yield to other tasks in the runtime.
-                     */
-                    task::yield_now().await;
-
-                    match action {
-                        Action::Forward { topic } => {
-                            /*
-                             * If a custom output was never defined, just take the
-                             * raw message and pass that along.
-                             */
-                            if output.is_empty() {
-                                output = String::from(&msg.msg);
+                        match modified {
+                            Ok(buffer) => output = buffer,
+                            Err(e) => {
+                                error!("Failed to modify the JSON, stopping actions: {}", e);
+                                self.stats.send((Stats::JsonActionFailed, 1)).await;
+                                continue_rules = false;
                             }
+                        }
+                    }
+
+                    Action::RateLimit {
+                        key,
+                        overflow_topic,
+                        ..
+                    } => {
+                        let template_id = rules::template_id_for(&rule, index);
+                        let field = |name: &str| rules::field_template_id(&template_id, name);
+                        let limiter = &engine.limiters[&template_id];
+
+                        /*
+                         * A key which cannot be rendered shouldn't exempt the message from the
+                         * limit, so it shares the bucket of messages without a key
+                         */
+                        let rendered = match key {
+                            Some(key) => hb.render(&field("key"), &hash).unwrap_or_else(|_| {
+                                error!("Failed to process the configured key: `{}`", key);
+                                String::new()
+                            }),
+                            None => String::new(),
+                        };
+
+                        if limiter.allow(&rendered) {
+                            continue;
+                        }
+
+                        debug!("The message is over the rate limit");
+                        self.stats
+                            .send((
+                                Stats::RateLimited {
+                                    rule: rules::name_for(&rule, rule_index),
+                                },
+                                1,
+                            ))
+                            .await;
 
-                            if let Ok(actual_topic) = hb.render_template(&topic, &hash) {
-                                debug!("Enqueueing for topic: `{}`", actual_topic);
-                                /*
-                                 * `output` is consumed by send_to_kafka, so the rest of the rules
-                                 * should be skipped.
-                                 */
-                                let kmsg = KafkaMessage::new(actual_topic, output);
-                                self.sender.send(kmsg).await;
-                                /*
-                                 * Ensure that we're allowing other tasks to execute when we pass
-                                 * things off to the channel
-                                 *
-                                 * See also https://github.com/stjepang/smol/issues/159
-                                 */
+                        if let Some(topic) = overflow_topic {
+                            if let Ok(actual_topic) = hb.render(&field("topic"), &hash) {
+                                if output.is_empty() {
+                                    output = String::from(&msg.msg);
+                                }
+                                self.outputs
+                                    .forward(KafkaMessage::new(actual_topic, output))
+                                    .await;
                                 task::yield_now().await;
-                                continue_rules = false;
                             } else {
                                 error!("Failed to process the configured topic: `{}`", topic);
                                 self.stats.send((Stats::TopicParseFailed, 1)).await;
                             }
-                            break;
                         }
+                        continue_rules = false;
+                        break;
+                    }
 
-                        Action::Merge { json, json_str: _ } => {
-                            debug!("merging JSON content: {}", json);
-                            if let Ok(buffer) =
-                                perform_merge(&msg.msg, &template_id_for(&rule, index), &rule_state)
-                            {
-                                output = buffer;
-                            } else {
+                    Action::Redact { paths, .. } => {
+                        let buffer = if output.is_empty() { &msg.msg } else { &output };
+                        let redactor = &engine.redactors[&rules::template_id_for(&rule, index)];
+                        let mut redactions = HashMap::new();
+
+                        /*
+                         * Without any paths the entire buffer is redacted, whether or not it is
+                         * JSON
+                         */
+                        let redacted = if paths.is_empty() {
+                            let (redacted, counts) = redactor.redact(buffer);
+                            redactions = counts;
+                            Ok(redacted)
+                        } else {
+                            modify_json(buffer, |json| {
+                                for path in paths.iter() {
+                                    if let Some(value) = json.pointer_mut(path) {
+                                        redactor.redact_value(value, &mut redactions);
+                                    }
+                                }
+                                Ok(())
+                            })
+                        };
+
+                        for (detector, count) in redactions {
+                            self.stats
+                                .send((Stats::Redacted { detector }, count as i64))
+                                .await;
+                        }
+
+                        match redacted {
+                            Ok(buffer) => output = buffer,
+                            Err(e) => {
+                                error!("Failed to redact the JSON, stopping actions: {}", e);
+                                self.stats.send((Stats::JsonActionFailed, 1)).await;
                                 continue_rules = false;
                             }
                         }
+                    }
+
+                    Action::Output { name } => {
+                        /*
+                         * Unlike forward, the output buffer is not consumed here so that
+                         * subsequent actions can continue to operate on it
+                         */
+                        let message = OutputMessage {
+                            msg: if output.is_empty() {
+                                String::from(&msg.msg)
+                            } else {
+                                output.clone()
+                            },
+                            variables: hash.clone(),
+                            syslog: msg.clone(),
+                        };
+                        self.outputs.send(&name, &message).await;
+                    }
 
-                        Action::Replace { template } => {
-                            let template_id = template_id_for(&rule, index);
+                    Action::Replace { template } => {
+                        let template_id = rules::template_id_for(&rule, index);
 
-                            debug!(
-                                "replacing content with template: {} ({})",
-                                template, template_id
-                            );
-                            if let Ok(rendered) = hb.render(&template_id, &hash) {
-                                output = rendered;
-                            }
+                        debug!(
+                            "replacing content with template: {} ({})",
+                            template, template_id
+                        );
+                        if let Ok(rendered) = hb.render(&template_id, &hash) {
+                            output = rendered;
                         }
+                    }
+
+                    Action::Sample { key, .. } => {
+                        let template_id = rules::template_id_for(&rule, index);
+                        let sampler = &engine.samplers[&template_id];
+
+                        /*
+                         * A key which cannot be rendered shouldn't cause every message to be
+                         * discarded, so the message is sampled as if there were no key
+                         */
+                        let id = rules::field_template_id(&template_id, "key");
+                        let rendered = key.as_ref().and_then(|key| match hb.render(&id, &hash) {
+                            Ok(rendered) => Some(rendered),
+                            Err(_) => {
+                                error!("Failed to process the configured key: `{}`", key);
+                                None
+                            }
+                        });
 
-                        Action::Stop => {
+                        if !sampler.sample(rendered.as_deref()) {
+                            debug!("Discarding the message which was not sampled");
+                            self.stats
+                                .send((
+                                    Stats::SampledOut {
+                                        rule: rules::name_for(&rule, rule_index),
+                                    },
+                                    1,
+                                ))
+                                .await;
                             continue_rules = false;
+                            break;
                         }
                     }
+
+                    Action::Stop => {
+                        continue_rules = false;
+                    }
                 }
             }
         }
+    }
+}
 
-        Ok(())
+/**
+ * Generate the variables available to the templates of every rule from the parsed message
+ */
+fn variables_for(msg: &parse::SyslogMessage) -> rules::Variables {
+    let mut hash = rules::Variables::new();
+    hash.insert("msg".to_string(), msg.msg.as_str().into());
+    hash.insert("version".to_string(), env!["CARGO_PKG_VERSION"].into());
+    hash.insert("iso8601".to_string(), Utc::now().to_rfc3339().into());
+    hash.insert(
+        "date".to_string(),
+        Utc::now().format("%Y-%m-%d").to_string().into(),
+    );
+
+    let optional = vec![
+        ("hostname", &msg.hostname),
+        ("appname", &msg.appname),
+        ("severity", &msg.severity),
+        ("facility", &msg.facility),
+        ("procid", &msg.procid),
+        ("msgid", &msg.msgid),
+    ];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            hash.insert(name.to_string(), value.as_str().into());
+        }
+    }
+
+    if let Some(timestamp) = &msg.timestamp {
+        hash.insert("timestamp".to_string(), timestamp.to_rfc3339().into());
+    }
+
+    /*
+     * Structured data is always present, even if empty, so that templates referring to it
+     * render consistently
+     */
+    if let Ok(sd) = serde_json::to_value(&msg.structured_data) {
+        hash.insert("sd".to_string(), sd);
     }
+    hash
 }
 
 /**
- * Generate a unique identifier for the given template
+ * perform_merge will generate the buffer resulting of the JSON merge, at the JSON pointer if one
+ * is given
  */
-fn template_id_for(rule: &Rule, index: usize) -> String {
-    format!("{}-{}", rule.uuid, index)
+fn perform_merge(
+    buffer: &str,
+    template_id: &str,
+    path: Option<&str>,
+    state: &RuleState,
+) -> Result<String, String> {
+    merge_with(buffer, path, state, || {
+        let rendered = state
+            .hb
+            .render(template_id, &state.variables)
+            .map_err(|e| format!("Failed to render the merge: {:?}", e))?;
+
+        serde_json::from_str(&rendered).map_err(|e| {
+            error!(
+                "The rendered merge was not valid JSON: {:?}\n{}",
+                e, rendered
+            );
+            "Rendered invalid JSON".to_string()
+        })
+    })
 }
 
 /**
- * precompile_templates will register templates for all the Merge and Replace actions from the
- * settings
- *
- * Will usually return a true, unless some setting parse failure occurred which is a critical
- * failure for the daemon
+ * perform_typed_merge will generate the buffer resulting of the JSON merge, substituting the
+ * variables into each of the string values of the JSON rather than rendering it as one template
  */
-fn precompile_templates(hb: &mut Handlebars, settings: Arc<Settings>) -> bool {
-    for rule in settings.rules.iter() {
-        for index in 0..rule.actions.len() {
-            match &rule.actions[index] {
-                Action::Merge { json: _, json_str } => {
-                    let template_id = template_id_for(rule, index);
-
-                    if let Some(template) = json_str {
-                        if let Err(e) = hb.register_template_string(&template_id, &template) {
-                            error!("Failed to register template! {}\n{}", e, template);
-                            return false;
-                        }
-                    } else {
-                        error!("Could not look up the json_str for a Merge action");
-                        return false;
-                    }
-                }
-                Action::Replace { template } => {
-                    let template_id = format!("{}-{}", rule.uuid, index);
-                    if let Err(e) = hb.register_template_string(&template_id, &template) {
-                        error!("Failed to register template! {}\n{}", e, template);
-                        return false;
-                    }
-                }
-                _ => {}
-            }
-        }
-    }
-    true
+fn perform_typed_merge(
+    buffer: &str,
+    template_id: &str,
+    json: &serde_json::Value,
+    coerce: &HashMap<String, Coercion>,
+    path: Option<&str>,
+    state: &RuleState,
+) -> Result<String, String> {
+    merge_with(buffer, path, state, || {
+        render_typed(template_id, json, coerce, state)
+    })
 }
 
 /**
- * precompile_jmespath will pre-generate all the necessary JMESPath::Variable objects from the
- * configuration file and shove thoe in the map given to it
+ * Render each of the string values of the JSON with their precompiled templates, converting those
+ * at the JSON pointers in `coerce` to the requested types
  */
-fn precompile_jmespath(map: &mut JmesPathExpressions, settings: Arc<Settings>) -> bool {
-    for rule in settings.rules.iter() {
-        if let Some(expression) = &rule.jmespath {
-            if !map.contains_key(expression) {
-                if let Ok(compiled) = jmespath::compile(&expression) {
-                    map.insert(expression.to_string(), compiled);
-                } else {
-                    error!("Failed to compile the JMESPath expression: {}", expression);
-                    return false;
-                }
-            }
+fn render_typed(
+    template_id: &str,
+    json: &serde_json::Value,
+    coerce: &HashMap<String, Coercion>,
+    state: &RuleState,
+) -> Result<serde_json::Value, String> {
+    let mut rendered_json = json.clone();
+
+    for (pointer, _) in rules::string_values(json) {
+        let rendered = state
+            .hb
+            .render(
+                &rules::typed_template_id(template_id, &pointer),
+                &state.variables,
+            )
+            .map_err(|e| format!("Failed to render the value {}: {:?}", pointer, e))?;
+
+        if let Some(value) = rendered_json.pointer_mut(&pointer) {
+            *value = match coerce.get(&pointer) {
+                Some(coercion) => coerce_value(rendered, *coercion),
+                None => serde_json::Value::String(rendered),
+            };
         }
     }
-    true
+    Ok(rendered_json)
 }
 
 /**
- * perform_merge will generate the buffer resulting of the JSON merge
+ * Merge the JSON produced by the render function into the buffer
  */
-fn perform_merge(buffer: &str, template_id: &str, state: &RuleState) -> Result<String, String> {
-    if let Ok(mut msg_json) = serde_json::from_str(&buffer) {
-        if let Ok(rendered) = state.hb.render(template_id, &state.variables) {
-            let to_merge: serde_json::Value = serde_json::from_str(&rendered)
-                .expect("Failed to deserialize our rendered to_merge_str");
+fn merge_with<F>(
+    buffer: &str,
+    path: Option<&str>,
+    state: &RuleState,
+    render: F,
+) -> Result<String, String>
+where
+    F: FnOnce() -> Result<serde_json::Value, String>,
+{
+    if let Ok(mut msg_json) = serde_json::from_str::<serde_json::Value>(&buffer) {
+        let to_merge = render()?;
 
-            /*
-             * If the administrator configured the merge incorrectly, just pass the buffer along un-merged
-             */
-            if !to_merge.is_object() {
-                error!("Merge requested was not a JSON object: {}", to_merge);
-                state.stats.send((Stats::MergeTargetNotJsonError, 1));
-                return Ok(buffer.to_string());
-            }
+        /*
+         * If the administrator configured the merge incorrectly, just pass the buffer along un-merged
+         */
+        if !to_merge.is_object() {
+            error!("Merge requested was not a JSON object: {}", to_merge);
+            let _ = state.stats.try_send((Stats::MergeTargetNotJsonError, 1));
+            return Ok(buffer.to_string());
+        }
 
-            merge::merge(&mut msg_json, &to_merge);
+        let target = match path {
+            Some(path) => pointer::entry(&mut msg_json, path),
+            None => Some(&mut msg_json),
+        };
 
-            if let Ok(output) = serde_json::to_string(&msg_json) {
-                return Ok(output);
+        /*
+         * Merging into a string or number would replace it, so leave the message as it is
+         */
+        match target {
+            Some(target) if target.is_object() || target.is_array() || target.is_null() => {
+                merge::merge(target, &to_merge)
+            }
+            _ => {
+                debug!("Unable to merge into {:?} of {}", path, buffer);
+                let _ = state.stats.try_send((Stats::MergePathError, 1));
+                return Ok(buffer.to_string());
             }
         }
+
+        if let Ok(output) = serde_json::to_string(&msg_json) {
+            return Ok(output);
+        }
         Err("Failed to merge and serialize".to_string())
     } else {
         error!("Failed to parse as JSON, stopping actions: {}", buffer);
-        state.stats.send((Stats::MergeInvalidJsonError, 1));
+        let _ = state.stats.try_send((Stats::MergeInvalidJsonError, 1));
         Err("Not JSON".to_string())
     }
 }
 
+/**
+ * Set the JSON pointer in the buffer to the rendered value
+ */
+fn perform_set(
+    buffer: &str,
+    template_id: &str,
+    path: &str,
+    value: &serde_json::Value,
+    coerce: Option<Coercion>,
+    state: &RuleState,
+) -> Result<String, String> {
+    /*
+     * The coercion can only apply to a value which is a single template, whose pointer is empty
+     */
+    let mut coercions = HashMap::new();
+    if let Some(coercion) = coerce {
+        coercions.insert("".to_string(), coercion);
+    }
+    let rendered = render_typed(template_id, value, &coercions, state)?;
+
+    modify_json(buffer, |json| {
+        if pointer::set(json, path, rendered) {
+            Ok(())
+        } else {
+            Err(format!("Unable to set {}", path))
+        }
+    })
+}
+
+/**
+ * Parse the buffer as JSON, modify it, and serialize it again
+ */
+fn modify_json<F>(buffer: &str, modify: F) -> Result<String, String>
+where
+    F: FnOnce(&mut serde_json::Value) -> Result<(), String>,
+{
+    let mut json: serde_json::Value =
+        serde_json::from_str(buffer).map_err(|_| "Not JSON".to_string())?;
+    modify(&mut json)?;
+    serde_json::to_string(&json).map_err(|e| format!("Failed to serialize: {:?}", e))
+}
+
+/**
+ * Convert the rendered value to the requested type, leaving it as a string if it cannot be
+ */
+fn coerce_value(rendered: String, coercion: Coercion) -> serde_json::Value {
+    let coerced = match coercion {
+        Coercion::Int => rendered.trim().parse::<i64>().ok().map(|i| i.into()),
+        Coercion::Float => rendered
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(serde_json::Value::Number),
+        Coercion::Bool => rendered.trim().parse::<bool>().ok().map(|b| b.into()),
+        Coercion::Json => serde_json::from_str(&rendered).ok(),
+    };
+
+    coerced.unwrap_or_else(|| {
+        warn!("Unable to coerce `{}` to {:?}", rendered, coercion);
+        serde_json::Value::String(rendered)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::kafka::KafkaSender;
     use async_std::sync::channel;
 
     /**
@@ -355,7 +1002,7 @@ mod tests {
      */
     fn rule_state<'a>(
         hb: &'a handlebars::Handlebars<'a>,
-        hash: &'a HashMap<String, String>,
+        hash: &'a rules::Variables,
     ) -> RuleState<'a> {
         let (unused_sender, _) = channel(1);
         RuleState {
@@ -371,10 +1018,10 @@ mod tests {
         let template_id = "1";
         hb.register_template_string(&template_id, "{}");
 
-        let hash = HashMap::<String, String>::new();
+        let hash = rules::Variables::new();
         let state = rule_state(&hb, &hash);
 
-        let output = perform_merge("{}", template_id, &state);
+        let output = perform_merge("{}", template_id, None, &state);
         assert_eq!(output, Ok("{}".to_string()));
     }
 
@@ -387,10 +1034,10 @@ mod tests {
         let template_id = "1";
         hb.register_template_string(&template_id, "[1]");
 
-        let hash = HashMap::<String, String>::new();
+        let hash = rules::Variables::new();
         let state = rule_state(&hb, &hash);
 
-        let output = perform_merge("{}", template_id, &state)?;
+        let output = perform_merge("{}", template_id, None, &state)?;
         assert_eq!(output, "{}".to_string());
         Ok(())
     }
@@ -404,10 +1051,10 @@ mod tests {
         let template_id = "1";
         hb.register_template_string(&template_id, "{}");
 
-        let hash = HashMap::<String, String>::new();
+        let hash = rules::Variables::new();
         let state = rule_state(&hb, &hash);
 
-        let output = perform_merge("invalid", template_id, &state);
+        let output = perform_merge("invalid", template_id, None, &state);
         let expected = Err("Not JSON".to_string());
         assert_eq!(output, expected);
     }
@@ -421,10 +1068,10 @@ mod tests {
         let template_id = "1";
         hb.register_template_string(&template_id, r#"{"hello":1}"#);
 
-        let hash = HashMap::<String, String>::new();
+        let hash = rules::Variables::new();
         let state = rule_state(&hb, &hash);
 
-        let output = perform_merge("{}", template_id, &state);
+        let output = perform_merge("{}", template_id, None, &state);
         assert_eq!(output, Ok("{\"hello\":1}".to_string()));
     }
 
@@ -437,53 +1084,734 @@ mod tests {
         let template_id = "1";
         hb.register_template_string(&template_id, r#"{"hello":"{{name}}"}"#);
 
-        let mut hash = HashMap::<String, String>::new();
-        hash.insert("name".to_string(), "world".to_string());
+        let mut hash = rules::Variables::new();
+        hash.insert("name".to_string(), "world".into());
         let state = rule_state(&hb, &hash);
 
-        let output = perform_merge("{}", template_id, &state);
+        let output = perform_merge("{}", template_id, None, &state);
         assert_eq!(output, Ok("{\"hello\":\"world\"}".to_string()));
     }
 
+    /**
+     * A variable which renders to invalid JSON should fail the merge rather than panic
+     */
     #[test]
-    fn test_precompile_templates_merge() {
+    fn merge_with_invalid_rendered_json() {
         let mut hb = Handlebars::new();
-        let settings = Arc::new(load("test/configs/single-rule-with-merge.yml"));
-        // Assuming that we're going to register the template with this id
-        let template_id = format!("{}-{}", settings.rules[0].uuid, 0);
+        let template_id = "1";
+        hb.register_template_string(&template_id, r#"{"hello":"{{name}}"}"#);
 
-        let result = precompile_templates(&mut hb, settings.clone());
-        assert!(result);
-        assert!(hb.has_template(&template_id));
+        let mut hash = rules::Variables::new();
+        hash.insert("name".to_string(), r#"back\slash"#.into());
+        let state = rule_state(&hb, &hash);
+
+        let output = perform_merge("{}", template_id, None, &state);
+        assert!(output.is_err());
     }
 
+    /**
+     * A path which runs into an array in the message should leave it unmerged
+     */
     #[test]
-    fn test_precompile_templates_replace() {
+    fn merge_with_array_in_path() {
         let mut hb = Handlebars::new();
-        let settings = Arc::new(load("test/configs/single-rule-with-replace.yml"));
-        // Assuming that we're going to register the template with this id
-        let template_id = format!("{}-{}", settings.rules[0].uuid, 0);
+        let template_id = "1";
+        hb.register_template_string(&template_id, r#"{"hello":1}"#)
+            .expect("Failed to register");
+
+        let hash = rules::Variables::new();
+        let state = rule_state(&hb, &hash);
+
+        let output = perform_merge(r#"{"meta":[1]}"#, template_id, Some("/meta/user"), &state);
+        assert_eq!(output, Ok(r#"{"meta":[1]}"#.to_string()));
+    }
+
+    /**
+     * A path which indexes past the end of an array in the message should leave it unmerged
+     */
+    #[test]
+    fn merge_with_index_out_of_range() {
+        let mut hb = Handlebars::new();
+        let template_id = "1";
+        hb.register_template_string(&template_id, r#"{"hello":1}"#)
+            .expect("Failed to register");
+
+        let hash = rules::Variables::new();
+        let state = rule_state(&hb, &hash);
+
+        let output = perform_merge(r#"{"tags":["a"]}"#, template_id, Some("/tags/5"), &state);
+        assert_eq!(output, Ok(r#"{"tags":["a"]}"#.to_string()));
+    }
+
+    #[test]
+    fn merge_with_escaped_path() {
+        let mut hb = Handlebars::new();
+        let template_id = "1";
+        hb.register_template_string(&template_id, r#"{"hello":1}"#)
+            .expect("Failed to register");
+
+        let hash = rules::Variables::new();
+        let state = rule_state(&hb, &hash);
 
-        let result = precompile_templates(&mut hb, settings.clone());
-        assert!(result);
-        assert!(hb.has_template(&template_id));
+        let output = perform_merge(r#"{"a/b":{}}"#, template_id, Some("/a~1b"), &state);
+        assert_eq!(output, Ok(r#"{"a/b":{"hello":1}}"#.to_string()));
     }
 
     #[test]
-    fn test_precompile_jmespath() {
-        let settings = Arc::new(load("test/configs/single-rule-with-merge.yml"));
-        let mut map = JmesPathExpressions::new();
-        let result = precompile_jmespath(&mut map, settings.clone());
-        assert!(result);
-        let expected = settings.rules[0].jmespath.as_ref().unwrap();
-        assert!(map.contains_key(expected));
+    fn test_coerce_value() {
+        assert_eq!(
+            serde_json::json!(42),
+            coerce_value("42".to_string(), Coercion::Int)
+        );
+        assert_eq!(
+            serde_json::json!(true),
+            coerce_value("true".to_string(), Coercion::Bool)
+        );
+        assert_eq!(
+            serde_json::json!({"a": [1]}),
+            coerce_value(r#"{"a":[1]}"#.to_string(), Coercion::Json)
+        );
+        assert_eq!(
+            serde_json::json!("forty two"),
+            coerce_value("forty two".to_string(), Coercion::Int)
+        );
+    }
+
+    /**
+     * Create a Connection for the given configuration file which sends to Kafka through the
+     * given sender
+     */
+    fn connection_for(file: &str, sender: Sender<KafkaMessage>) -> Connection {
+        let settings = load(file);
+        let listen = settings.global.listen[0].clone();
+        let (stats, _) = channel(100);
+        let kafka = KafkaSender::new(sender, None);
+        let outputs = Arc::new(
+            Outputs::new(&settings.global.outputs, kafka, stats.clone())
+                .expect("Failed to create the outputs"),
+        );
+        let settings =
+            Arc::new(ReloadableSettings::new(file, settings).expect("Failed to compile the rules"));
+
+        Connection::new(
+            settings,
+            outputs,
+            stats,
+            listen.framing,
+            listen.max_frame_size,
+            listen.multiline,
+        )
+    }
+
+    #[test]
+    fn test_rate_limit_overflow_topic() {
+        task::block_on(async {
+            let (sender, receiver) = channel(10);
+            let connection = connection_for("test/configs/rules-with-rate-limit.yml", sender);
+
+            for _ in 0..3 {
+                connection
+                    .process_line(
+                        "<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - chatty"
+                            .to_string(),
+                        None,
+                    )
+                    .await;
+            }
+
+            let mut received = vec![];
+            while !receiver.is_empty() {
+                received.push(receiver.recv().await.expect("Failed to receive a message"));
+            }
+            assert_eq!(
+                vec![
+                    KafkaMessage::new("logs".to_string(), "chatty".to_string()),
+                    KafkaMessage::new("logs".to_string(), "chatty".to_string()),
+                    KafkaMessage::new("throttled-tyler".to_string(), "chatty".to_string()),
+                ],
+                received
+            );
+        });
+    }
+
+    #[test]
+    fn test_rate_limit_keyed() {
+        task::block_on(async {
+            let (sender, receiver) = channel(10);
+            let connection = connection_for("test/configs/rules-with-rate-limit.yml", sender);
+
+            for host in &["coconut", "banana", "coconut"] {
+                connection
+                    .process_line(
+                        format!(
+                            "<13>1 2020-04-18T15:16:09.956153-07:00 {} tyler - - - keyed",
+                            host
+                        ),
+                        None,
+                    )
+                    .await;
+            }
+
+            /*
+             * The second message from coconut is over its limit and dropped
+             */
+            let mut received = 0;
+            while !receiver.is_empty() {
+                receiver.recv().await.expect("Failed to receive a message");
+                received += 1;
+            }
+            assert_eq!(2, received);
+        });
+    }
+
+    #[test]
+    fn test_dedupe() {
+        task::block_on(async {
+            let (sender, receiver) = channel(10);
+            let connection = connection_for("test/configs/single-rule-with-dedupe.yml", sender);
+
+            for line in &["flap", "flap", "flap", "other"] {
+                connection
+                    .process_line(
+                        format!(
+                            "<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - {}",
+                            line
+                        ),
+                        None,
+                    )
+                    .await;
+            }
+
+            /*
+             * Nothing is summarized until the window has passed
+             */
+            let engine = connection.settings.current();
+            dedupe::close_expired(&engine, &connection.outputs, Instant::now()).await;
+            task::sleep(Duration::from_millis(200)).await;
+            dedupe::close_expired(&engine, &connection.outputs, Instant::now()).await;
+
+            let mut received = vec![];
+            while !receiver.is_empty() {
+                received.push(receiver.recv().await.expect("Failed to receive a message"));
+            }
+            assert_eq!(
+                vec![
+                    KafkaMessage::new("logs".to_string(), "flap".to_string()),
+                    KafkaMessage::new("logs".to_string(), "other".to_string()),
+                    KafkaMessage::new(
+                        "repeats".to_string(),
+                        "coconut: last message repeated 2 times".to_string()
+                    ),
+                ],
+                received
+            );
+        });
+    }
+
+    #[test]
+    fn test_drop_and_sample() {
+        task::block_on(async {
+            let (sender, receiver) = channel(10);
+            let connection = connection_for("test/configs/rules-with-drop-and-sample.yml", sender);
+
+            for line in &[
+                "sample 1", "sample 2", "sample 3", "sample 4", "noise", "hello",
+            ] {
+                connection
+                    .process_line(
+                        format!(
+                            "<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - {}",
+                            line
+                        ),
+                        None,
+                    )
+                    .await;
+            }
+
+            let mut received = vec![];
+            while !receiver.is_empty() {
+                received.push(receiver.recv().await.expect("Failed to receive a message"));
+            }
+            assert_eq!(
+                vec![
+                    KafkaMessage::new("sampled".to_string(), "sample 1".to_string()),
+                    KafkaMessage::new("sampled".to_string(), "sample 3".to_string()),
+                    KafkaMessage::new("logs".to_string(), "hello".to_string()),
+                ],
+                received
+            );
+        });
+    }
+
+    #[test]
+    fn test_sample_keyed() {
+        task::block_on(async {
+            let (sender, receiver) = channel(10);
+            let connection = connection_for("test/configs/rules-with-drop-and-sample.yml", sender);
+
+            /*
+             * The crc32 of `coconut` is 606994685, which is kept at 50 percent since
+             * 606994685 % 10000 = 4685, while the crc32 of `banana` is 59467727 which isn't
+             */
+            for _ in 0..4 {
+                for host in &["coconut", "banana"] {
+                    connection
+                        .process_line(
+                            format!(
+                                "<13>1 2020-04-18T15:16:09.956153-07:00 {} tyler - - - keyed {}",
+                                host, host
+                            ),
+                            None,
+                        )
+                        .await;
+                }
+            }
+
+            let mut received = vec![];
+            while !receiver.is_empty() {
+                received.push(receiver.recv().await.expect("Failed to receive a message"));
+            }
+            let expected: Vec<KafkaMessage> = (0..4)
+                .map(|_| KafkaMessage::new("keyed".to_string(), "keyed coconut".to_string()))
+                .collect();
+            assert_eq!(expected, received);
+        });
     }
 
     #[test]
-    fn test_precompile_jmespath_baddata() {
-        let settings = Arc::new(load("test/configs/single-rule-with-invalid-jmespath.yml"));
-        let mut map = JmesPathExpressions::new();
-        let result = precompile_jmespath(&mut map, settings.clone());
-        assert!(!result);
+    fn test_redact_json_paths() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection = connection_for("test/configs/single-rule-with-redact.yml", sender);
+
+            connection
+                .process_line(
+                    r#"<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - {"user":{"email":"tyler@example.com"},"contact":"admin@example.com","note":"ssn=123-45-6789 from 10.0.0.1"}"#
+                        .to_string(),
+                    None,
+                )
+                .await;
+
+            let expected = serde_json::json!({
+                "user": {"email": "[REDACTED]"},
+                "contact": "admin@example.com",
+                "note": "ssn=*** from ***",
+            });
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("logs".to_string(), expected.to_string()),
+                kmsg
+            );
+        });
+    }
+
+    #[test]
+    fn test_redact_buffer() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection = connection_for("test/configs/single-rule-with-redact.yml", sender);
+
+            connection
+                .process_line(
+                    "<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - login by tyler@example.com from 10.0.0.1".to_string(),
+                    None,
+                )
+                .await;
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new(
+                    "plain".to_string(),
+                    "login by [REDACTED] from [REDACTED]".to_string()
+                ),
+                kmsg
+            );
+        });
+    }
+
+    /**
+     * Ensure that the lines of a stack trace are joined, and that pending messages are processed
+     * when the connection closes
+     */
+    #[test]
+    fn test_read_logs_multiline() {
+        task::block_on(async {
+            let (sender, receiver) = channel(10);
+            let connection = connection_for("test/configs/listen-with-multiline.yml", sender);
+            let input = [
+                "<13>1 2020-04-18T15:16:09.956153-07:00 coconut java 1 - - Exception in thread main",
+                "<13>1 2020-04-18T15:16:09.956153-07:00 coconut java 1 - - at Main.main(Main.java:1)",
+                "<13>1 2020-04-18T15:16:09.956153-07:00 coconut python 2 - - Traceback",
+                "<13>1 2020-04-18T15:16:09.956153-07:00 coconut java 1 - - done",
+            ]
+            .join("\n");
+
+            connection
+                .read_logs(BufReader::new(input.as_bytes()), None)
+                .await
+                .expect("Failed to read the logs");
+
+            let mut received = vec![];
+            while !receiver.is_empty() {
+                let kmsg = receiver.recv().await.expect("Failed to receive a message");
+                received.push(format!("{:?}", kmsg));
+            }
+            assert_eq!(3, received.len());
+            assert!(received[0]
+                .contains(r#"msg: "Exception in thread main\nat Main.main(Main.java:1)""#));
+            assert!(received.iter().any(|r| r.contains(r#"msg: "Traceback""#)));
+            assert!(received.iter().any(|r| r.contains(r#"msg: "done""#)));
+        });
+    }
+
+    /**
+     * Ensure that a pending multi-line message is processed once it times out, while the
+     * connection remains open
+     */
+    #[test]
+    fn test_read_logs_multiline_timeout() {
+        use async_std::prelude::*;
+
+        task::block_on(async {
+            let (sender, receiver) = channel(10);
+            let connection = connection_for("test/configs/listen-with-multiline.yml", sender);
+
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind a listener");
+            let addr = listener.local_addr().expect("No local address");
+
+            task::spawn(async move {
+                let (stream, _) = listener.accept().await.expect("Failed to accept");
+                connection.read_logs(BufReader::new(stream), None).await
+            });
+
+            let mut client = async_std::net::TcpStream::connect(addr)
+                .await
+                .expect("Failed to connect");
+            client
+                .write_all(b"<13>1 2020-04-18T15:16:09.956153-07:00 coconut java 1 - - Exception\n<13>1 2020-04-18T15:16:09.956153-07:00 coconut java 1 - - at Main.main(Main.java:1)\n")
+                .await
+                .expect("Failed to write");
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new(
+                    "logs".to_string(),
+                    "Exception\nat Main.main(Main.java:1)".to_string()
+                ),
+                kmsg
+            );
+            drop(client);
+        });
+    }
+
+    /**
+     * Ensure that datagrams received over UDP are run through the rules and forwarded along
+     */
+    #[test]
+    fn test_read_datagrams() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection = connection_for("test/configs/udp-listener.yml", sender);
+
+            let socket = UdpSocket::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind a UDP socket");
+            let addr = socket
+                .local_addr()
+                .expect("Failed to get the local address");
+
+            task::spawn(async move { connection.read_datagrams(socket, 1024).await });
+
+            let client = UdpSocket::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind a UDP client socket");
+            client
+                .send_to(
+                    b"<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - hello\n",
+                    addr,
+                )
+                .await
+                .expect("Failed to send the datagram");
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert!(format!("{:?}", kmsg).contains(r#"msg: "hello""#));
+        });
+    }
+
+    /**
+     * Ensure that the key, headers, and partition of a forward action are rendered onto the
+     * message
+     */
+    #[test]
+    fn test_forward_with_key_and_headers() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection =
+                connection_for("test/configs/single-rule-with-forward-key.yml", sender);
+
+            connection
+                .process_line(
+                    "<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - 2 hello"
+                        .to_string(),
+                    None,
+                )
+                .await;
+
+            let mut expected = KafkaMessage::new("logs-tyler".to_string(), "2 hello".to_string());
+            expected.set_key("coconut".to_string());
+            expected.add_header("app".to_string(), "tyler".to_string());
+            expected.set_partition(2);
+            expected.set_partitioner(Partitioner::Murmur2);
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(expected, kmsg);
+        });
+    }
+
+    /**
+     * Ensure that rules can match on the header fields and that they, along with the structured
+     * data, are available to templates
+     */
+    #[test]
+    fn test_structured_data_variables() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection =
+                connection_for("test/configs/single-rule-with-structured-data.yml", sender);
+
+            connection
+                .process_line(
+                    r#"<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler 4321 ID47 [timeQuality tzKnown="1"] hello"#
+                        .to_string(),
+                    None,
+                )
+                .await;
+
+            let expected = KafkaMessage::new(
+                "logs-ID47".to_string(),
+                "4321 1 2020-04-18T15:16:09.956153-07:00 hello".to_string(),
+            );
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(expected, kmsg);
+        });
+    }
+
+    /**
+     * Ensure that rules can match on structured data parameters and compare severities
+     */
+    #[test]
+    fn test_match_sd_and_compare() {
+        task::block_on(async {
+            let (sender, receiver) = channel(2);
+            let connection = connection_for("test/configs/rules-with-sd-and-compare.yml", sender);
+
+            connection
+                .process_line(
+                    r#"<14>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - [origin ip="10.0.0.5"] hello"#
+                        .to_string(),
+                    None,
+                )
+                .await;
+            connection
+                .process_line(
+                    "<11>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - oops".to_string(),
+                    None,
+                )
+                .await;
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("internal".to_string(), "hello".to_string()),
+                kmsg
+            );
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("alerts".to_string(), "oops".to_string()),
+                kmsg
+            );
+        });
+    }
+
+    /**
+     * Ensure that compound conditions merge the variables captured by their matching conditions
+     */
+    #[test]
+    fn test_compound_conditions() {
+        task::block_on(async {
+            let (sender, receiver) = channel(3);
+            let connection = connection_for("test/configs/rule-with-conditions.yml", sender);
+
+            for line in &[
+                "<14>1 2020-04-18T15:16:09.956153-07:00 coconut nginx - - - status=503",
+                "<14>1 2020-04-18T15:16:09.956153-07:00 coconut apache - - - status=503",
+                "<14>1 2020-04-18T15:16:09.956153-07:00 coconut nginx - - - status=200",
+            ] {
+                connection.process_line(line.to_string(), None).await;
+            }
+
+            for (topic, msg) in &[
+                ("nginx-503", "status=503"),
+                ("other", "status=503"),
+                ("other", "status=200"),
+            ] {
+                let kmsg = receiver.recv().await.expect("Failed to receive a message");
+                assert_eq!(KafkaMessage::new(topic.to_string(), msg.to_string()), kmsg);
+            }
+        });
+    }
+
+    /**
+     * Ensure that the envelope action produces JSON which can then be merged with
+     */
+    #[test]
+    fn test_envelope_with_merge() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection = connection_for("test/configs/single-rule-with-envelope.yml", sender);
+            let peer: SocketAddr = "10.0.0.1:514".parse().expect("Failed to parse the address");
+
+            connection
+                .process_line(
+                    "<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - hello".to_string(),
+                    Some(peer),
+                )
+                .await;
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            let debugged = format!("{:?}", kmsg);
+            assert!(debugged.contains(r#"topic: "envelopes""#));
+            assert!(debugged.contains(r#"\"hostname\":\"coconut\""#));
+            assert!(debugged.contains(r#"\"msg\":\"hello\""#));
+            assert!(debugged.contains(r#"\"peer\":\"10.0.0.1:514\""#));
+            assert!(debugged.contains(r#"\"environment\":\"production\""#));
+        });
+    }
+
+    /**
+     * Ensure that JSON messages are exposed to templates when the rule asks for them
+     */
+    #[test]
+    fn test_parse_json() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection = connection_for("test/configs/single-rule-with-parse-json.yml", sender);
+
+            connection
+                .process_line(
+                    r#"<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - {"meta":{"topic":"audit"},"tags":["a","b"]}"#
+                        .to_string(),
+                    None,
+                )
+                .await;
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("logs-audit".to_string(), "a,b,".to_string()),
+                kmsg
+            );
+        });
+    }
+
+    /**
+     * Ensure that typed merges preserve and coerce types, even for values containing quotes
+     */
+    #[test]
+    fn test_typed_merge() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection =
+                connection_for("test/configs/single-rule-with-typed-merge.yml", sender);
+
+            connection
+                .process_line(
+                    r#"<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - {"status":503,"user":"ty\"ler","tags":[1,2]}"#
+                        .to_string(),
+                    None,
+                )
+                .await;
+
+            let expected = serde_json::json!({
+                "status": 503,
+                "user": "ty\"ler",
+                "tags": [1, 2],
+                "code": 503,
+                "who": "ty\"ler",
+                "list": [1, 2],
+                "meta": {
+                    "ratio": 0.5,
+                    "enabled": true,
+                },
+            });
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("logs".to_string(), expected.to_string()),
+                kmsg
+            );
+        });
+    }
+
+    /**
+     * Ensure that the remove, rename, set, and merge actions modify the JSON at their pointers
+     */
+    #[test]
+    fn test_json_actions() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let connection =
+                connection_for("test/configs/single-rule-with-json-actions.yml", sender);
+
+            connection
+                .process_line(
+                    r#"<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler 4321 - - {"user":"tyler","password":"hunter2","other":1}"#
+                        .to_string(),
+                    None,
+                )
+                .await;
+
+            let expected = serde_json::json!({
+                "other": 1,
+                "meta": {
+                    "username": "tyler",
+                    "host": "coconut",
+                    "pid": 4321,
+                    "source": "hotdog",
+                },
+            });
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("logs".to_string(), expected.to_string()),
+                kmsg
+            );
+        });
+    }
+
+    /**
+     * Ensure that the output action sends to the named output and continues with the actions
+     */
+    #[test]
+    fn test_output_action() {
+        task::block_on(async {
+            let (sender, receiver) = channel(2);
+            let connection = connection_for("test/configs/single-rule-with-output.yml", sender);
+            connection
+                .process_line(
+                    "<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler - - - hello".to_string(),
+                    None,
+                )
+                .await;
+
+            let mut audit = KafkaMessage::new("audit".to_string(), "hello".to_string());
+            audit.set_key("coconut".to_string());
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(audit, kmsg);
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("logs".to_string(), "hello".to_string()),
+                kmsg
+            );
+        });
     }
 }
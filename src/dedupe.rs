@@ -0,0 +1,275 @@
+use crate::kafka::KafkaMessage;
+use crate::output::Outputs;
+use crate::reload::ReloadableSettings;
+use crate::rules::{RuleEngine, Variables};
+/**
+ * The dedupe module contains the Deduplicator which tracks the windows of the dedupe actions.
+ *
+ * The first message with a key opens a window, and every further message with the same key is
+ * suppressed and counted until the window is closed by the sweeploop, which forwards the summary
+ */
+use async_std::{sync::Arc, task};
+use log::*;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/**
+ * The number of windows each dedupe action may have open, after which the oldest window is closed
+ * early to make room, since the key defaults to the whole message and so is unbounded
+ */
+const MAX_WINDOWS: usize = 10_000;
+
+/**
+ * How often the sweeploop looks for windows which have passed
+ */
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct Window {
+    opened: Instant,
+    /**
+     * The number of messages suppressed in the window
+     */
+    repeats: u64,
+    /**
+     * The rendered topic and the variables of the first message, for rendering the summary
+     */
+    topic: String,
+    variables: Variables,
+}
+
+/**
+ * A window which has been closed, along with what is needed to forward its summary
+ */
+#[derive(Debug, PartialEq)]
+pub struct Closed {
+    pub topic: String,
+    pub variables: Variables,
+    pub repeats: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Observation {
+    /**
+     * The message opened a new window, which may have closed the oldest window early
+     */
+    Opened(Option<Closed>),
+    /**
+     * The message repeats one in an open window and should be suppressed
+     */
+    Repeated,
+}
+
+#[derive(Debug)]
+pub struct Deduplicator {
+    window: Duration,
+    /**
+     * The open windows, keyed by the key of the window
+     */
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Deduplicator {
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Observe the message with the key, opening a window for it if there isn't one already. The
+     * topic and variables are kept with the window for rendering its summary
+     */
+    pub fn observe(
+        &self,
+        key: &str,
+        topic: &str,
+        variables: &Variables,
+        now: Instant,
+    ) -> Observation {
+        let mut windows = self.windows.lock();
+
+        if let Some(window) = windows.get_mut(key) {
+            window.repeats += 1;
+            return Observation::Repeated;
+        }
+
+        let mut evicted = None;
+        if windows.len() >= MAX_WINDOWS {
+            let oldest = windows
+                .iter()
+                .min_by_key(|(_, window)| window.opened)
+                .map(|(key, _)| key.to_string());
+            if let Some(oldest) = oldest {
+                evicted = windows.remove(&oldest).map(closed);
+            }
+        }
+
+        windows.insert(
+            key.to_string(),
+            Window {
+                opened: now,
+                repeats: 0,
+                topic: topic.to_string(),
+                variables: variables.clone(),
+            },
+        );
+        Observation::Opened(evicted)
+    }
+
+    /**
+     * Close every window which has passed by `now`
+     */
+    pub fn expire(&self, now: Instant) -> Vec<Closed> {
+        let mut windows = self.windows.lock();
+        let expired: Vec<String> = windows
+            .iter()
+            .filter(|(_, window)| now.saturating_duration_since(window.opened) >= self.window)
+            .map(|(key, _)| key.to_string())
+            .collect();
+
+        expired
+            .iter()
+            .filter_map(|key| windows.remove(key))
+            .map(closed)
+            .collect()
+    }
+}
+
+fn closed(window: Window) -> Closed {
+    Closed {
+        topic: window.topic,
+        variables: window.variables,
+        repeats: window.repeats,
+    }
+}
+
+/**
+ * Forward the summary of the closed window of the dedupe action, unless nothing was suppressed
+ */
+pub async fn summarize(engine: &RuleEngine, outputs: &Outputs, template_id: &str, closed: Closed) {
+    if closed.repeats == 0 {
+        return;
+    }
+
+    let mut variables = closed.variables;
+    variables.insert("repeats".to_string(), closed.repeats.into());
+
+    match engine.hb.render(template_id, &variables) {
+        Ok(summary) => {
+            outputs
+                .forward(KafkaMessage::new(closed.topic, summary))
+                .await
+        }
+        Err(e) => error!("Failed to render the summary: {:?}", e),
+    }
+}
+
+/**
+ * Close the windows of every dedupe action which have passed by `now`, forwarding their summaries
+ */
+pub async fn close_expired(engine: &RuleEngine, outputs: &Outputs, now: Instant) {
+    for (template_id, deduplicator) in engine.deduplicators.iter() {
+        for closed in deduplicator.expire(now) {
+            summarize(engine, outputs, template_id, closed).await;
+        }
+    }
+}
+
+/**
+ * sweeploop periodically closes the windows of the currently active rules, for as long as hotdog
+ * is running
+ */
+pub async fn sweeploop(settings: Arc<ReloadableSettings>, outputs: Arc<Outputs>) {
+    loop {
+        task::sleep(SWEEP_INTERVAL).await;
+        close_expired(&settings.current(), &outputs, Instant::now()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> Variables {
+        let mut variables = Variables::new();
+        variables.insert("hostname".to_string(), "coconut".into());
+        variables
+    }
+
+    #[test]
+    fn test_observe_and_expire() {
+        let dedupe = Deduplicator::new(Duration::from_secs(10));
+        let now = Instant::now();
+        let vars = variables();
+
+        assert_eq!(
+            Observation::Opened(None),
+            dedupe.observe("hello", "logs", &vars, now)
+        );
+        assert_eq!(
+            Observation::Repeated,
+            dedupe.observe("hello", "logs", &vars, now)
+        );
+        assert_eq!(
+            Observation::Repeated,
+            dedupe.observe("hello", "logs", &vars, now)
+        );
+        assert_eq!(
+            Observation::Opened(None),
+            dedupe.observe("world", "logs", &vars, now + Duration::from_secs(5))
+        );
+
+        /*
+         * Only the window for hello has passed
+         */
+        let closed = dedupe.expire(now + Duration::from_secs(10));
+        assert_eq!(
+            vec![Closed {
+                topic: "logs".to_string(),
+                variables: vars.clone(),
+                repeats: 2,
+            }],
+            closed
+        );
+
+        /*
+         * Once closed, the next message opens a new window
+         */
+        assert_eq!(
+            Observation::Opened(None),
+            dedupe.observe("hello", "logs", &vars, now + Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn test_expire_nothing() {
+        let dedupe = Deduplicator::new(Duration::from_secs(10));
+        assert!(dedupe.expire(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_oldest_window_is_evicted() {
+        let dedupe = Deduplicator::new(Duration::from_secs(10));
+        let now = Instant::now();
+        let vars = Variables::new();
+
+        for i in 0..MAX_WINDOWS {
+            let at = now + Duration::from_micros(i as u64);
+            dedupe.observe(&i.to_string(), "logs", &vars, at);
+        }
+        dedupe.observe("0", "logs", &vars, now);
+
+        match dedupe.observe("new", "logs", &vars, now + Duration::from_secs(1)) {
+            Observation::Opened(Some(closed)) => assert_eq!(1, closed.repeats),
+            other => panic!("The oldest window was not evicted: {:?}", other),
+        }
+
+        let windows = dedupe.windows.lock();
+        assert_eq!(MAX_WINDOWS, windows.len());
+        assert!(!windows.contains_key("0"));
+        assert!(windows.contains_key("new"));
+    }
+}
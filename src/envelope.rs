@@ -0,0 +1,118 @@
+use crate::parse::{StructuredData, SyslogMessage};
+/**
+ * The envelope module is responsible for serializing the entire parsed syslog message, along with
+ * the details of how it was received, into a JSON document for downstream consumers which want a
+ * structured record rather than just the message text
+ */
+use chrono::prelude::*;
+use std::net::SocketAddr;
+
+#[derive(Debug, Serialize)]
+pub struct Envelope<'a> {
+    /**
+     * The timestamp from the syslog header, if it had one
+     */
+    pub timestamp: Option<String>,
+    pub hostname: Option<&'a str>,
+    pub appname: Option<&'a str>,
+    pub severity: Option<&'a str>,
+    pub facility: Option<&'a str>,
+    pub procid: Option<&'a str>,
+    pub msgid: Option<&'a str>,
+    pub structured_data: &'a StructuredData,
+    pub msg: &'a str,
+    /**
+     * When hotdog received the message
+     */
+    pub received_at: String,
+    /**
+     * The address of the sender, if it is known
+     */
+    pub peer: Option<String>,
+}
+
+impl<'a> Envelope<'a> {
+    /**
+     * Create the envelope for the syslog message, using `body` as its `msg` so that the results of
+     * earlier actions, such as replace, are preserved
+     */
+    pub fn new(
+        syslog: &'a SyslogMessage,
+        body: &'a str,
+        received_at: DateTime<Utc>,
+        peer: Option<SocketAddr>,
+    ) -> Self {
+        Envelope {
+            timestamp: syslog.timestamp.map(|t| t.to_rfc3339()),
+            hostname: syslog.hostname.as_deref(),
+            appname: syslog.appname.as_deref(),
+            severity: syslog.severity.as_deref(),
+            facility: syslog.facility.as_deref(),
+            procid: syslog.procid.as_deref(),
+            msgid: syslog.msgid.as_deref(),
+            structured_data: &syslog.structured_data,
+            msg: body,
+            received_at: received_at.to_rfc3339(),
+            peer: peer.map(|p| p.to_string()),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_line;
+
+    #[test]
+    fn test_envelope() {
+        let syslog = parse_line(
+            r#"<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler 4321 ID47 [origin ip="10.0.0.1"] hi"#
+                .to_string(),
+        )
+        .expect("Failed to parse");
+        let received_at = Utc.ymd(2020, 4, 18).and_hms(22, 16, 10);
+        let peer: SocketAddr = "10.0.0.1:514".parse().expect("Failed to parse the address");
+
+        let json = Envelope::new(&syslog, "hello", received_at, Some(peer))
+            .to_json()
+            .expect("Failed to serialize");
+        let value: serde_json::Value = serde_json::from_str(&json).expect("Invalid JSON");
+
+        assert_eq!(
+            serde_json::json!({
+                "timestamp": "2020-04-18T15:16:09.956153-07:00",
+                "hostname": "coconut",
+                "appname": "tyler",
+                "severity": "notice",
+                "facility": "user",
+                "procid": "4321",
+                "msgid": "ID47",
+                "structured_data": {"origin": {"ip": "10.0.0.1"}},
+                "msg": "hello",
+                "received_at": "2020-04-18T22:16:10+00:00",
+                "peer": "10.0.0.1:514",
+            }),
+            value
+        );
+    }
+
+    #[test]
+    fn test_envelope_without_header_fields() {
+        let syslog = SyslogMessage {
+            msg: "hi".to_string(),
+            ..Default::default()
+        };
+        let envelope = Envelope::new(&syslog, &syslog.msg, Utc::now(), None);
+        let value: serde_json::Value =
+            serde_json::from_str(&envelope.to_json().expect("Failed to serialize"))
+                .expect("Invalid JSON");
+
+        assert_eq!(serde_json::Value::Null, value["hostname"]);
+        assert_eq!(serde_json::Value::Null, value["peer"]);
+        assert_eq!("hi", value["msg"]);
+    }
+}
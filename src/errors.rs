@@ -4,8 +4,23 @@
 
 #[derive(Debug)]
 pub enum HotdogError {
-    IOError { err: std::io::Error },
+    IOError {
+        err: std::io::Error,
+    },
+    ConfigError {
+        err: config::ConfigError,
+    },
     KafkaConnectError,
+    /**
+     * The rules in the configuration could not be compiled
+     */
+    InvalidRules,
+    /**
+     * A message could not be sent to an output
+     */
+    OutputError {
+        err: String,
+    },
 }
 
 impl std::convert::From<std::io::Error> for HotdogError {
@@ -13,3 +28,9 @@ impl std::convert::From<std::io::Error> for HotdogError {
         HotdogError::IOError { err }
     }
 }
+
+impl std::convert::From<config::ConfigError> for HotdogError {
+    fn from(err: config::ConfigError) -> HotdogError {
+        HotdogError::ConfigError { err }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::settings::Framing;
+/**
+ * The framing module is responsible for splitting a stream of bytes into individual syslog
+ * messages, supporting both of the framing methods described in RFC 6587:
+ *
+ *  - octet-counting, where each message is prefixed with its length, e.g. `11 hello world`
+ *  - non-transparent-framing, where each message is terminated by a LF (or NUL) character
+ */
+use async_std::{
+    io::{self, BufRead, BufReader, Read},
+    prelude::*,
+};
+use futures::future::poll_fn;
+use std::pin::Pin;
+use std::task::Poll;
+
+/**
+ * The maximum number of digits allowed in the MSG-LEN of an octet-counted frame
+ */
+const MAX_OCTET_COUNT_DIGITS: u64 = 10;
+
+/**
+ * FrameReader wraps a buffered stream and yields the syslog messages framed within it
+ */
+pub struct FrameReader<R> {
+    reader: BufReader<R>,
+    framing: Framing,
+    /**
+     * The largest frame which will be accepted, in bytes
+     */
+    max_frame_size: usize,
+}
+
+impl<R: Read + std::marker::Unpin> FrameReader<R> {
+    pub fn new(reader: BufReader<R>, framing: Framing, max_frame_size: usize) -> Self {
+        FrameReader {
+            reader,
+            framing,
+            max_frame_size,
+        }
+    }
+
+    /**
+     * Read the next message from the stream, returning None once the stream has been closed
+     */
+    pub async fn next_frame(&mut self) -> io::Result<Option<String>> {
+        let first = match self.skip_separators().await? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+
+        let octet_counted = match self.framing {
+            /*
+             * Syslog messages always start with `<PRI>`, so a leading digit can only be the
+             * MSG-LEN of an octet-counted frame
+             */
+            Framing::Auto => first.is_ascii_digit(),
+            Framing::OctetCounted => true,
+            Framing::NonTransparent => false,
+        };
+
+        let frame = if octet_counted {
+            self.read_octet_counted().await?
+        } else {
+            self.read_non_transparent().await?
+        };
+
+        match String::from_utf8(frame) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    /**
+     * Wait until the next frame has begun to arrive, returning false if the stream has been closed
+     * instead.
+     *
+     * Unlike next_frame(), this can be safely abandoned, such as by a timeout, without losing any
+     * of the next frame
+     */
+    pub async fn ready(&mut self) -> io::Result<bool> {
+        Ok(self.skip_separators().await?.is_some())
+    }
+
+    /**
+     * Consume any trailers or stray line endings between frames, returning the first byte of the
+     * next frame without consuming it
+     */
+    async fn skip_separators(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            let reader = &mut self.reader;
+            let (next, used) = poll_fn(|cx| match Pin::new(&mut *reader).poll_fill_buf(cx) {
+                Poll::Ready(Ok(buf)) => {
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok((None, 0)));
+                    }
+                    match buf.iter().position(|b| !is_separator(*b)) {
+                        Some(index) => Poll::Ready(Ok((Some(buf[index]), index))),
+                        None => Poll::Ready(Ok((None, buf.len()))),
+                    }
+                }
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            })
+            .await?;
+
+            Pin::new(&mut self.reader).consume(used);
+
+            /*
+             * Nothing was consumed and no byte was found means we have reached the end of the
+             * stream
+             */
+            if next.is_some() || used == 0 {
+                return Ok(next);
+            }
+        }
+    }
+
+    /**
+     * Read a frame which is terminated by a LF or NUL character, or the end of the stream
+     */
+    async fn read_non_transparent(&mut self) -> io::Result<Vec<u8>> {
+        let mut frame = vec![];
+
+        loop {
+            let reader = &mut self.reader;
+            let (done, used) = poll_fn(|cx| match Pin::new(&mut *reader).poll_fill_buf(cx) {
+                Poll::Ready(Ok(buf)) => {
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok((true, 0)));
+                    }
+                    match buf.iter().position(|b| *b == b'\n' || *b == b'\0') {
+                        Some(index) => {
+                            frame.extend_from_slice(&buf[..index]);
+                            Poll::Ready(Ok((true, index + 1)))
+                        }
+                        None => {
+                            frame.extend_from_slice(buf);
+                            Poll::Ready(Ok((false, buf.len())))
+                        }
+                    }
+                }
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            })
+            .await?;
+
+            Pin::new(&mut self.reader).consume(used);
+
+            if frame.len() > self.max_frame_size {
+                return Err(too_large(frame.len() as u64, self.max_frame_size));
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        if frame.last() == Some(&b'\r') {
+            frame.pop();
+        }
+        Ok(frame)
+    }
+
+    /**
+     * Read a frame of the form `MSG-LEN SP SYSLOG-MSG`
+     */
+    async fn read_octet_counted(&mut self) -> io::Result<Vec<u8>> {
+        let mut length = vec![];
+        (&mut self.reader)
+            .take(MAX_OCTET_COUNT_DIGITS + 1)
+            .read_until(b' ', &mut length)
+            .await?;
+
+        if length.pop() != Some(b' ') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "octet-counted frame is missing its MSG-LEN",
+            ));
+        }
+
+        let length: u64 = std::str::from_utf8(&length)
+            .ok()
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "octet-counted frame has an invalid MSG-LEN",
+                )
+            })?;
+
+        /*
+         * The MSG-LEN is checked before reading the frame so that a sender cannot make us buffer
+         * an arbitrary amount of data
+         */
+        if length > self.max_frame_size as u64 {
+            return Err(too_large(length, self.max_frame_size));
+        }
+
+        let mut frame = vec![];
+        (&mut self.reader)
+            .take(length)
+            .read_to_end(&mut frame)
+            .await?;
+
+        if (frame.len() as u64) < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "stream closed before the octet-counted frame was complete",
+            ));
+        }
+        Ok(frame)
+    }
+}
+
+fn too_large(length: u64, max_frame_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "frame of {} bytes exceeds the max_frame_size of {} bytes",
+            length, max_frame_size
+        ),
+    )
+}
+
+/**
+ * Bytes which may appear between frames and should never start a frame
+ */
+fn is_separator(byte: u8) -> bool {
+    byte == b'\n' || byte == b'\r' || byte == b'\0'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    /**
+     * Read every frame out of the given input
+     */
+    fn frames(input: &str, framing: Framing) -> io::Result<Vec<String>> {
+        frames_up_to(input, framing, 1024)
+    }
+
+    /**
+     * Read every frame out of the given input, accepting frames up to the given size
+     */
+    fn frames_up_to(
+        input: &str,
+        framing: Framing,
+        max_frame_size: usize,
+    ) -> io::Result<Vec<String>> {
+        task::block_on(async {
+            let mut reader =
+                FrameReader::new(BufReader::new(input.as_bytes()), framing, max_frame_size);
+            let mut frames = vec![];
+            while let Some(frame) = reader.next_frame().await? {
+                frames.push(frame);
+            }
+            Ok(frames)
+        })
+    }
+
+    #[test]
+    fn test_non_transparent() {
+        let result = frames("<13>hello\n<13>world\r\n\n", Framing::NonTransparent).unwrap();
+        assert_eq!(vec!["<13>hello", "<13>world"], result);
+    }
+
+    #[test]
+    fn test_non_transparent_nul_trailer() {
+        let result = frames("<13>hello\0<13>world", Framing::NonTransparent).unwrap();
+        assert_eq!(vec!["<13>hello", "<13>world"], result);
+    }
+
+    #[test]
+    fn test_non_transparent_leading_digits() {
+        let result = frames("5 hello\n", Framing::NonTransparent).unwrap();
+        assert_eq!(vec!["5 hello"], result);
+    }
+
+    #[test]
+    fn test_octet_counted() {
+        let result = frames(
+            "23 <13>Exception:\n  at foo11 <13>goodbye",
+            Framing::OctetCounted,
+        )
+        .unwrap();
+        assert_eq!(vec!["<13>Exception:\n  at foo", "<13>goodbye"], result);
+    }
+
+    #[test]
+    fn test_octet_counted_with_trailing_newlines() {
+        let result = frames("9 <13>hello\n9 <13>world\n", Framing::OctetCounted).unwrap();
+        assert_eq!(vec!["<13>hello", "<13>world"], result);
+    }
+
+    #[test]
+    fn test_octet_counted_invalid_length() {
+        assert!(frames("<13>hello\n", Framing::OctetCounted).is_err());
+    }
+
+    #[test]
+    fn test_octet_counted_truncated() {
+        assert!(frames("50 <13>hello", Framing::OctetCounted).is_err());
+    }
+
+    #[test]
+    fn test_octet_counted_too_large() {
+        let result = frames_up_to("99999999 <13>hello", Framing::OctetCounted, 1024);
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            result.expect_err("Frame should be rejected").kind()
+        );
+
+        let result = frames_up_to("9 <13>hello", Framing::OctetCounted, 9).unwrap();
+        assert_eq!(vec!["<13>hello"], result);
+    }
+
+    #[test]
+    fn test_non_transparent_too_large() {
+        let result = frames_up_to("<13>hello\n", Framing::NonTransparent, 8);
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            result.expect_err("Frame should be rejected").kind()
+        );
+    }
+
+    #[test]
+    fn test_auto_detect() {
+        let result = frames("<13>hello\n15 <13>multi\nline\n<13>world\n", Framing::Auto).unwrap();
+        assert_eq!(vec!["<13>hello", "<13>multi\nline\n", "<13>world"], result);
+    }
+
+    #[test]
+    fn test_ready() {
+        task::block_on(async {
+            let input = "\n\n<13>hello\n";
+            let mut reader =
+                FrameReader::new(BufReader::new(input.as_bytes()), Framing::Auto, 1024);
+
+            assert!(reader.ready().await.unwrap());
+            assert!(reader.ready().await.unwrap());
+            assert_eq!(
+                Some("<13>hello".to_string()),
+                reader.next_frame().await.unwrap()
+            );
+            assert!(!reader.ready().await.unwrap());
+        });
+    }
+}
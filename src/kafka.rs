@@ -1,5 +1,8 @@
+use crate::partition;
+use crate::settings::Partitioner;
+use crate::spool::Spool;
 use crate::status::{Statistic, Stats};
-use async_std::sync::{channel, Receiver, Sender};
+use async_std::sync::{channel, Arc, Receiver, Sender};
 /**
  * The Kafka module contains all the tooling/code necessary for connecting hotdog to Kafka for
  * sending log lines along as Kafka messages
@@ -10,23 +13,140 @@ use rdkafka::client::DefaultClientContext;
 use rdkafka::config::ClientConfig;
 use rdkafka::consumer::{BaseConsumer, Consumer};
 use rdkafka::error::{KafkaError, RDKafkaError};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 /**
- * KafkaMessage just carries a message and its destination topic between tasks
+ * How often the spool should be checked for messages to replay while Kafka is healthy
  */
-#[derive(Debug)]
+const REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+/**
+ * How long to wait between replay attempts while Kafka is failing to accept messages
+ */
+const UNHEALTHY_REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+/**
+ * How long to wait for the partition count of a topic when partitioning messages
+ */
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+/**
+ * How long the partition count of a topic is used before it is looked up again, so that
+ * partitions added to the topic are eventually used
+ */
+const PARTITION_COUNT_TTL: Duration = Duration::from_secs(300);
+/**
+ * How long to wait before looking up the partition count of a topic again after failing to
+ */
+const FAILED_LOOKUP_TTL: Duration = Duration::from_secs(30);
+
+/**
+ * The partition count of each topic, or None if it could not be looked up, along with when it
+ * should be looked up again
+ */
+type PartitionCounts = HashMap<String, (Option<i32>, Instant)>;
+
+/**
+ * KafkaMessage just carries a message, its destination topic, and its optional key and headers
+ * between tasks
+ */
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct KafkaMessage {
     topic: String,
     msg: String,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    partition: Option<i32>,
+    #[serde(default)]
+    partitioner: Option<Partitioner>,
 }
 
 impl KafkaMessage {
     pub fn new(topic: String, msg: String) -> KafkaMessage {
-        KafkaMessage { topic, msg }
+        KafkaMessage {
+            topic,
+            msg,
+            key: None,
+            headers: HashMap::new(),
+            partition: None,
+            partitioner: None,
+        }
+    }
+
+    pub fn set_key(&mut self, key: String) {
+        self.key = Some(key);
+    }
+
+    pub fn add_header(&mut self, name: String, value: String) {
+        self.headers.insert(name, value);
+    }
+
+    pub fn set_partition(&mut self, partition: i32) {
+        self.partition = Some(partition);
+    }
+
+    pub fn set_partitioner(&mut self, partitioner: Partitioner) {
+        self.partitioner = Some(partitioner);
+    }
+
+    /**
+     * Convert the headers into the form needed by rdkafka, returning None if there are no headers
+     */
+    fn owned_headers(&self) -> Option<OwnedHeaders> {
+        if self.headers.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.headers
+                .iter()
+                .fold(OwnedHeaders::new(), |headers, (name, value)| {
+                    headers.add(name, value.as_str())
+                }),
+        )
+    }
+}
+
+/**
+ * KafkaSender is the handle connections use to pass messages along to Kafka.
+ *
+ * When a spool has been configured, messages will be written to the spool instead while Kafka is
+ * failing to accept them
+ */
+#[derive(Clone)]
+pub struct KafkaSender {
+    tx: Sender<KafkaMessage>,
+    spool: Option<Arc<Spool>>,
+    healthy: Arc<AtomicBool>,
+}
+
+impl KafkaSender {
+    pub fn new(tx: Sender<KafkaMessage>, spool: Option<Arc<Spool>>) -> KafkaSender {
+        KafkaSender {
+            tx,
+            spool,
+            healthy: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub async fn send(&self, kmsg: KafkaMessage) {
+        if let Some(spool) = &self.spool {
+            /*
+             * Once messages have been spooled, new messages must be spooled behind them in order
+             * to preserve their ordering until the spool has been replayed
+             */
+            if (!spool.is_empty() || !self.healthy.load(Ordering::SeqCst))
+                && spool.push(&kmsg).await
+            {
+                return;
+            }
+        }
+        self.tx.send(kmsg).await;
     }
 }
 
@@ -40,9 +160,20 @@ pub struct Kafka {
      * ::new() and the .connect() function
      */
     producer: Option<FutureProducer<DefaultClientContext>>,
+    /**
+     * The consumer created to validate the connection is kept around for looking up the partition
+     * counts of topics
+     */
+    metadata: Option<Arc<BaseConsumer>>,
     stats: Sender<Statistic>,
     rx: Receiver<KafkaMessage>,
     tx: Sender<KafkaMessage>,
+    spool: Option<Arc<Spool>>,
+    /**
+     * Tracks whether the most recent delivery to Kafka succeeded, used to determine when spooled
+     * messages should be replayed
+     */
+    healthy: Arc<AtomicBool>,
 }
 
 impl Kafka {
@@ -50,12 +181,23 @@ impl Kafka {
         let (tx, rx) = channel(message_max);
         Kafka {
             producer: None,
+            metadata: None,
             stats,
             tx,
             rx,
+            spool: None,
+            healthy: Arc::new(AtomicBool::new(true)),
         }
     }
 
+    /**
+     * Spool messages to disk which cannot be delivered to Kafka, replaying them once Kafka is
+     * available again
+     */
+    pub fn spool_to(&mut self, spool: Arc<Spool>) {
+        self.spool = Some(spool);
+    }
+
     /**
      * connect() will inherently validate the configuration and perform a blocking call to the
      * configured bootstrap.servers in order to determine whether Kafka is reachable.
@@ -103,6 +245,7 @@ impl Kafka {
                     .create()
                     .expect("Failed to create the Kafka producer!"),
             );
+            self.metadata = Some(Arc::new(consumer));
 
             return true;
         }
@@ -116,8 +259,12 @@ impl Kafka {
      * get_sender() will return a cloned reference to the sender suitable for tasks or threads to
      * consume and take ownership of
      */
-    pub fn get_sender(&self) -> Sender<KafkaMessage> {
-        self.tx.clone()
+    pub fn get_sender(&self) -> KafkaSender {
+        KafkaSender {
+            tx: self.tx.clone(),
+            spool: self.spool.clone(),
+            healthy: self.healthy.clone(),
+        }
     }
 
     /**
@@ -129,14 +276,25 @@ impl Kafka {
         }
 
         let producer = self.producer.as_ref().unwrap();
+        let mut partitions = Partitions::new(self.metadata.clone());
+
+        if let Some(spool) = &self.spool {
+            task::spawn(replayloop(
+                spool.clone(),
+                producer.clone(),
+                Partitions::new(self.metadata.clone()),
+                self.stats.clone(),
+                self.healthy.clone(),
+            ));
+        }
 
         loop {
             if let Ok(kmsg) = self.rx.recv().await {
                 debug!("Sending to Kafka: {:?}", kmsg);
-                /* Note, setting the `K` (key) type on FutureRecord to a string
-                 * even though we're explicitly not sending a key
-                 */
+                let partition = partitions.partition_for(&kmsg).await;
                 let stats = self.stats.clone();
+                let spool = self.spool.clone();
+                let healthy = self.healthy.clone();
 
                 let start_time = Instant::now();
                 let producer = producer.clone();
@@ -148,60 +306,26 @@ impl Kafka {
                 task::yield_now().await;
 
                 task::spawn(async move {
-                    let record = FutureRecord::<String, String>::to(&kmsg.topic).payload(&kmsg.msg);
                     /*
                      * Intentionally setting the timeout_ms to -1 here so this blocks forever if the
                      * outbound librdkafka queue is full. This will block up the crossbeam channel
                      * properly and cause messages to begin to be dropped, rather than buffering
                      * "forever" inside of hotdog
                      */
-                    if let Ok(delivery_result) = producer.send(record, -1 as i64).await {
-                        match delivery_result {
-                            Ok(_) => {
-                                stats
-                                    .send((Stats::KafkaMsgSubmitted { topic: kmsg.topic }, 1))
-                                    .await;
+                    let delivery = produce(&producer, &kmsg, partition, -1);
+
+                    match delivered(delivery, &kmsg.topic, start_time, &stats).await {
+                        Ok(()) => healthy.store(true, Ordering::SeqCst),
+                        Err(err) => {
+                            if is_retriable(&err) {
+                                healthy.store(false, Ordering::SeqCst);
+
                                 /*
-                                 * dipstick only supports u64 timers anyways, but as_micros() can
-                                 * give a u128 (!).
+                                 * Hold onto the message so it can be replayed once Kafka is
+                                 * accepting messages again
                                  */
-                                if let Ok(elapsed) = start_time.elapsed().as_micros().try_into() {
-                                    stats.send((Stats::KafkaMsgSent, elapsed)).await;
-                                } else {
-                                    error!("Could not collect message time because the duration couldn't fit in an i64, yikes");
-                                }
-                            }
-                            Err((err, _)) => {
-                                match err {
-                                    /*
-                                     * err_type will be one of RdKafkaError types defined:
-                                     * https://docs.rs/rdkafka/0.23.1/rdkafka/error/enum.RDKafkaError.html
-                                     */
-                                    KafkaError::MessageProduction(err_type) => {
-                                        error!(
-                                            "Failed to send message to Kafka due to: {}",
-                                            err_type
-                                        );
-                                        stats
-                                            .send((
-                                                Stats::KafkaMsgErrored {
-                                                    errcode: metric_name_for(err_type),
-                                                },
-                                                1,
-                                            ))
-                                            .await;
-                                    }
-                                    _ => {
-                                        error!("Failed to send message to Kafka!");
-                                        stats
-                                            .send((
-                                                Stats::KafkaMsgErrored {
-                                                    errcode: String::from("generic"),
-                                                },
-                                                1,
-                                            ))
-                                            .await;
-                                    }
+                                if let Some(spool) = spool {
+                                    spool.push(&kmsg).await;
                                 }
                             }
                         }
@@ -212,6 +336,271 @@ impl Kafka {
     }
 }
 
+/**
+ * Partitions determines the partitions for messages which are partitioned by their key, caching
+ * the partition counts of their topics
+ */
+struct Partitions {
+    metadata: Option<Arc<BaseConsumer>>,
+    counts: PartitionCounts,
+}
+
+impl Partitions {
+    fn new(metadata: Option<Arc<BaseConsumer>>) -> Self {
+        Partitions {
+            metadata,
+            counts: PartitionCounts::new(),
+        }
+    }
+
+    /**
+     * Determine the partition the message should be produced to, returning None when the
+     * producer's configured partitioner should decide
+     */
+    async fn partition_for(&mut self, kmsg: &KafkaMessage) -> Option<i32> {
+        if kmsg.partition.is_some() {
+            return kmsg.partition;
+        }
+
+        let partitioner = kmsg.partitioner?;
+        let key = kmsg.key.as_ref()?;
+
+        let count = match self.counts.get(&kmsg.topic) {
+            Some((count, expires)) if Instant::now() < *expires => *count,
+            _ => {
+                let count = self.partition_count(&kmsg.topic).await;
+                /*
+                 * Failures are cached too, so that an unreachable cluster doesn't mean a lookup
+                 * for every message
+                 */
+                let ttl = match count {
+                    Some(_) => PARTITION_COUNT_TTL,
+                    None => FAILED_LOOKUP_TTL,
+                };
+                self.counts
+                    .insert(kmsg.topic.clone(), (count, Instant::now() + ttl));
+                count
+            }
+        }?;
+
+        Some(partition::partition_for(partitioner, key.as_bytes(), count))
+    }
+
+    /**
+     * Look up the number of partitions for the topic.
+     *
+     * Fetching the metadata blocks, so it is done on a blocking thread rather than the executor.
+     * The sendloop still waits for the result, but this only happens once PARTITION_COUNT_TTL has
+     * passed since the topic was last looked up
+     */
+    async fn partition_count(&self, topic: &str) -> Option<i32> {
+        let consumer = self.metadata.clone()?;
+        let topic = topic.to_string();
+
+        task::spawn_blocking(move || {
+            let metadata = match consumer.fetch_metadata(Some(&topic), METADATA_TIMEOUT) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    error!("Failed to fetch the partitions for {}: {:?}", topic, e);
+                    return None;
+                }
+            };
+
+            metadata
+                .topics()
+                .iter()
+                .find(|t| t.name() == topic)
+                .map(|t| t.partitions().len() as i32)
+                .filter(|count| *count > 0)
+        })
+        .await
+    }
+}
+
+/**
+ * Produce the message to Kafka, returning the future for its delivery.
+ *
+ * `block_ms` is how long to wait for space in librdkafka's queue, or -1 to wait forever
+ */
+fn produce(
+    producer: &FutureProducer<DefaultClientContext>,
+    kmsg: &KafkaMessage,
+    partition: Option<i32>,
+    block_ms: i64,
+) -> DeliveryFuture {
+    let mut record = FutureRecord::<String, String>::to(&kmsg.topic).payload(&kmsg.msg);
+
+    if let Some(key) = &kmsg.key {
+        record = record.key(key);
+    }
+    if let Some(headers) = kmsg.owned_headers() {
+        record = record.headers(headers);
+    }
+    if let Some(partition) = partition {
+        record = record.partition(partition);
+    }
+    producer.send(record, block_ms)
+}
+
+/**
+ * Wait for the message produced at `start_time` to be delivered, recording the outcome
+ */
+async fn delivered(
+    delivery: DeliveryFuture,
+    topic: &str,
+    start_time: Instant,
+    stats: &Sender<Statistic>,
+) -> Result<(), KafkaError> {
+    let err = match delivery.await {
+        Ok(Ok(_)) => {
+            stats
+                .send((
+                    Stats::KafkaMsgSubmitted {
+                        topic: topic.to_string(),
+                    },
+                    1,
+                ))
+                .await;
+            /*
+             * dipstick only supports u64 timers anyways, but as_micros() can
+             * give a u128 (!).
+             */
+            if let Ok(elapsed) = start_time.elapsed().as_micros().try_into() {
+                stats.send((Stats::KafkaMsgSent, elapsed)).await;
+            } else {
+                error!("Could not collect message time because the duration couldn't fit in an i64, yikes");
+            }
+            return Ok(());
+        }
+        Ok(Err((err, _))) => err,
+        Err(_) => KafkaError::Canceled,
+    };
+
+    match err {
+        /*
+         * err_type will be one of RdKafkaError types defined:
+         * https://docs.rs/rdkafka/0.23.1/rdkafka/error/enum.RDKafkaError.html
+         */
+        KafkaError::MessageProduction(err_type) => {
+            error!("Failed to send message to Kafka due to: {}", err_type);
+            stats
+                .send((
+                    Stats::KafkaMsgErrored {
+                        errcode: metric_name_for(err_type),
+                    },
+                    1,
+                ))
+                .await;
+        }
+        _ => {
+            error!("Failed to send message to Kafka!");
+            stats
+                .send((
+                    Stats::KafkaMsgErrored {
+                        errcode: String::from("generic"),
+                    },
+                    1,
+                ))
+                .await;
+        }
+    }
+    Err(err)
+}
+
+/**
+ * replayloop should be called in a task and will never return, replaying spooled messages in the
+ * order they were spooled whenever Kafka is accepting messages.
+ *
+ * A segment is only removed from the spool once every message in it has been delivered, otherwise
+ * the messages from the first which wasn't delivered are kept at the front of the spool, so that
+ * they are replayed again in order.
+ *
+ * While Kafka is unhealthy, replays happen infrequently and serve as a probe to determine whether
+ * Kafka has become available again
+ */
+async fn replayloop(
+    spool: Arc<Spool>,
+    producer: FutureProducer<DefaultClientContext>,
+    mut partitions: Partitions,
+    stats: Sender<Statistic>,
+    healthy: Arc<AtomicBool>,
+) -> ! {
+    spool.report().await;
+
+    loop {
+        if healthy.load(Ordering::SeqCst) {
+            task::sleep(REPLAY_INTERVAL).await;
+        } else {
+            task::sleep(UNHEALTHY_REPLAY_INTERVAL).await;
+        }
+
+        if let Some(segment) = spool.take_segment().await {
+            info!("Replaying {} spooled messages", segment.messages.len());
+            let start_time = Instant::now();
+
+            /*
+             * Never block the executor waiting for space in librdkafka's queue, a full queue fails
+             * the delivery and the rest of the segment is replayed later
+             */
+            let mut deliveries = vec![];
+            for kmsg in segment.messages.iter() {
+                let partition = partitions.partition_for(kmsg).await;
+                deliveries.push(produce(&producer, kmsg, partition, 0));
+            }
+
+            let mut undelivered = None;
+            for (index, delivery) in deliveries.into_iter().enumerate() {
+                let topic = &segment.messages[index].topic;
+
+                if let Err(err) = delivered(delivery, topic, start_time, &stats).await {
+                    /*
+                     * Messages Kafka will never accept are dropped, as they are when they are
+                     * first sent
+                     */
+                    if is_retriable(&err) && undelivered.is_none() {
+                        undelivered = Some(index);
+                    }
+                }
+            }
+
+            match undelivered {
+                Some(index) => {
+                    warn!(
+                        "Failed to replay {} spooled messages, they will be replayed again",
+                        segment.messages.len() - index
+                    );
+                    healthy.store(false, Ordering::SeqCst);
+                    spool.requeue(segment, index).await;
+                }
+                None => {
+                    healthy.store(true, Ordering::SeqCst);
+                    spool.finish(segment).await;
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Determine whether the error indicates that Kafka is unavailable, meaning the message could be
+ * delivered later
+ */
+fn is_retriable(err: &KafkaError) -> bool {
+    match err {
+        KafkaError::MessageProduction(err_type) => matches!(
+            err_type,
+            RDKafkaError::MessageTimedOut
+                | RDKafkaError::QueueFull
+                | RDKafkaError::AllBrokersDown
+                | RDKafkaError::BrokerTransportFailure
+                | RDKafkaError::RequestTimedOut
+                | RDKafkaError::BrokerNotAvailable
+                | RDKafkaError::LeaderNotAvailable
+        ),
+        _ => false,
+    }
+}
+
 /**
  * A simple function for formatting the generated strings from RDKafkaError to be useful as metric
  * names for systems like statsd
@@ -226,6 +615,7 @@ fn metric_name_for(err: RDKafkaError) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rdkafka::message::Headers;
 
     /**
      * Test that trying to connect to a nonexistent cluster returns false
@@ -243,6 +633,91 @@ mod tests {
         assert_eq!(false, k.connect(&conf, Some(Duration::from_secs(1))));
     }
 
+    /**
+     * Messages spooled before keys and headers existed should still be readable
+     */
+    #[test]
+    fn test_deserialize_without_key() {
+        let kmsg: KafkaMessage =
+            serde_json::from_str(r#"{"topic":"logs","msg":"hello"}"#).expect("Failed to parse");
+        assert_eq!(KafkaMessage::new("logs".into(), "hello".into()), kmsg);
+        assert!(kmsg.owned_headers().is_none());
+    }
+
+    #[test]
+    fn test_owned_headers() {
+        let mut kmsg = KafkaMessage::new("logs".into(), "hello".into());
+        kmsg.add_header("host".into(), "coconut".into());
+        let headers = kmsg.owned_headers().expect("No headers were created");
+        assert_eq!(1, headers.count());
+    }
+
+    #[test]
+    fn test_partition_for_explicit_partition() {
+        let mut partitions = Partitions::new(None);
+        let mut kmsg = KafkaMessage::new("logs".into(), "hello".into());
+        kmsg.set_key("coconut".into());
+        kmsg.set_partition(3);
+        kmsg.set_partitioner(Partitioner::Murmur2);
+
+        let partition = task::block_on(partitions.partition_for(&kmsg));
+        assert_eq!(Some(3), partition);
+    }
+
+    #[test]
+    fn test_partition_for_cached_count() {
+        let mut partitions = Partitions::new(None);
+        let mut kmsg = KafkaMessage::new("logs".into(), "hello".into());
+        kmsg.set_key("kafka".into());
+        kmsg.set_partitioner(Partitioner::Murmur2);
+
+        partitions.counts.insert(
+            "logs".to_string(),
+            (Some(100), Instant::now() + PARTITION_COUNT_TTL),
+        );
+        assert_eq!(Some(80), task::block_on(partitions.partition_for(&kmsg)));
+    }
+
+    /**
+     * An expired count should be looked up again, and a failure to look it up cached
+     */
+    #[test]
+    fn test_partition_for_expired_count() {
+        let mut partitions = Partitions::new(None);
+        let mut kmsg = KafkaMessage::new("logs".into(), "hello".into());
+        kmsg.set_key("kafka".into());
+        kmsg.set_partitioner(Partitioner::Murmur2);
+
+        partitions
+            .counts
+            .insert("logs".to_string(), (Some(100), Instant::now()));
+        assert_eq!(None, task::block_on(partitions.partition_for(&kmsg)));
+
+        let (count, expires) = partitions.counts["logs"];
+        assert_eq!(None, count);
+        assert!(expires > Instant::now());
+    }
+
+    #[test]
+    fn test_partition_for_without_key() {
+        let mut partitions = Partitions::new(None);
+        let mut kmsg = KafkaMessage::new("logs".into(), "hello".into());
+        kmsg.set_partitioner(Partitioner::Murmur2);
+
+        let partition = task::block_on(partitions.partition_for(&kmsg));
+        assert_eq!(None, partition);
+    }
+
+    #[test]
+    fn test_is_retriable() {
+        assert!(is_retriable(&KafkaError::MessageProduction(
+            RDKafkaError::MessageTimedOut
+        )));
+        assert!(!is_retriable(&KafkaError::MessageProduction(
+            RDKafkaError::MessageSizeTooLarge
+        )));
+    }
+
     /**
      * Tests for converting RDKafkaError strings into statsd suitable metric strings
      */
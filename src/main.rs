@@ -25,17 +25,35 @@ use dipstick::{Input, Prefixed, Statsd};
 use log::*;
 
 mod connection;
+mod dedupe;
+mod envelope;
 mod errors;
+mod framing;
 mod kafka;
 mod merge;
+mod multiline;
+mod output;
+mod output_file;
+mod output_http;
+mod output_syslog;
 mod parse;
+mod partition;
+mod pointer;
+mod rate_limit;
+mod redact;
+mod reload;
 mod rules;
+mod sample;
 mod serve;
 mod serve_plain;
 mod serve_tls;
+mod serve_udp;
 mod settings;
+mod spool;
 mod status;
 
+use output::Outputs;
+use reload::ReloadableSettings;
 use serve::*;
 use settings::*;
 
@@ -69,7 +87,12 @@ async fn main() -> Result<(), errors::HotdogError> {
         .get_matches();
 
     let settings_file = matches.value_of("config").unwrap_or("hotdog.yml");
-    let settings = Arc::new(settings::load(settings_file));
+    let reloadable = Arc::new(ReloadableSettings::new(
+        settings_file,
+        settings::load(settings_file),
+    )?);
+    let engine = reloadable.current();
+    let settings = engine.settings.clone();
     let metrics = Arc::new(
         Statsd::send_to(&settings.global.metrics.statsd)
             .expect("Failed to create Statsd recorder")
@@ -84,6 +107,7 @@ async fn main() -> Result<(), errors::HotdogError> {
         task::spawn(status::status_server(
             format!("{}:{}", st.address, st.port),
             stats.clone(),
+            reloadable.clone(),
         ));
     }
 
@@ -92,32 +116,67 @@ async fn main() -> Result<(), errors::HotdogError> {
     });
 
     if let Some(test_file) = matches.value_of("test") {
-        return rules::test_rules(&test_file, settings).await;
+        return rules::test_rules(&test_file, engine).await;
     }
 
-    let addr = format!(
-        "{}:{}",
-        settings.global.listen.address, settings.global.listen.port
-    );
+    reload::reload_on_sighup(reloadable.clone())?;
+
+    let sender = start_kafka(&settings, stats_sender.clone())?;
+    let outputs = Arc::new(Outputs::new(
+        &settings.global.outputs,
+        sender,
+        stats_sender.clone(),
+    )?);
+
+    task::spawn(dedupe::sweeploop(reloadable.clone(), outputs.clone()));
+
+    let listeners: Vec<_> = settings
+        .global
+        .listen
+        .iter()
+        .map(|listen| {
+            let state = ServerState {
+                settings: reloadable.clone(),
+                stats: stats_sender.clone(),
+                outputs: outputs.clone(),
+                listen: listen.clone(),
+            };
+            task::spawn(serve(state))
+        })
+        .collect();
+
+    futures::future::try_join_all(listeners).await?;
+    Ok(())
+}
+
+/**
+ * Serve the listener described by the ServerState with the appropriate Server implementation
+ */
+async fn serve(state: ServerState) -> Result<(), errors::HotdogError> {
+    let addr = format!("{}:{}", state.listen.address, state.listen.port);
     info!("Listening on: {}", addr);
 
-    let state = ServerState {
-        settings: settings.clone(),
-        stats: stats_sender,
-    };
+    if state.listen.protocol == Protocol::Udp {
+        if state.listen.tls != TlsType::None {
+            warn!("TLS is not supported for UDP listeners, ignoring the `tls` configuration");
+        }
+        info!("Serving {} in UDP mode", addr);
+        let mut server = crate::serve_udp::UdpServer {};
+        return server.accept_loop(&addr, state).await;
+    }
 
-    match &settings.global.listen.tls {
+    match &state.listen.tls {
         TlsType::CertAndKey {
             cert: _,
             key: _,
             ca: _,
         } => {
-            info!("Serving in TLS mode");
+            info!("Serving {} in TLS mode", addr);
             let mut server = crate::serve_tls::TlsServer::new(&state);
             server.accept_loop(&addr, state).await
         }
         _ => {
-            info!("Serving in plaintext mode");
+            info!("Serving {} in plaintext mode", addr);
             let mut server = crate::serve_plain::PlaintextServer {};
             server.accept_loop(&addr, state).await
         }
@@ -2,6 +2,11 @@
  * Disabling this lint because I don't want to fix it in this imported code
  */
 #![allow(clippy::clone_double_ref)]
+/*
+ * Only merge() is used, since merge_in() doesn't handle every message safely, see
+ * pointer::entry()
+ */
+#![allow(dead_code)]
 /**
  * This module is from the crate json_value_merge
  *  <https://github.com/jmfiaschi/json_value_merge/>
@@ -0,0 +1,287 @@
+use crate::parse::SyslogMessage;
+use crate::settings::Multiline;
+/**
+ * The multiline module is responsible for joining messages which span several syslog lines, such
+ * as stack traces, back into a single message before the rules are applied.
+ *
+ * Lines are aggregated separately for each hostname, appname, and procid, so that the lines of
+ * different applications sharing a connection are not mixed together
+ */
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+type Key = (Option<String>, Option<String>, Option<String>);
+
+/**
+ * A complete message, along with the address of the sender of its first line if it is known
+ */
+pub type Complete = (SyslogMessage, Option<SocketAddr>);
+
+struct Pending {
+    msg: SyslogMessage,
+    peer: Option<SocketAddr>,
+    lines: usize,
+    /**
+     * When the message will be considered complete if no more lines arrive
+     */
+    deadline: Instant,
+}
+
+pub struct Aggregator {
+    conf: Multiline,
+    pending: HashMap<Key, Pending>,
+}
+
+impl Aggregator {
+    pub fn new(conf: &Multiline) -> Self {
+        Aggregator {
+            conf: conf.clone(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /**
+     * Add the message received from the peer at `now`, returning any messages which it completes
+     */
+    pub fn push(
+        &mut self,
+        msg: SyslogMessage,
+        peer: Option<SocketAddr>,
+        now: Instant,
+    ) -> Vec<Complete> {
+        let key = (
+            msg.hostname.clone(),
+            msg.appname.clone(),
+            msg.procid.clone(),
+        );
+        let timeout = Duration::from_millis(self.conf.timeout_ms);
+        let mut complete = vec![];
+
+        if self.is_continuation(&msg.msg) {
+            if let Some(pending) = self.pending.get_mut(&key) {
+                pending.msg.msg.push('\n');
+                pending.msg.msg.push_str(&msg.msg);
+                pending.lines += 1;
+                pending.deadline = now + timeout;
+
+                if pending.lines >= self.conf.max_lines {
+                    if let Some(pending) = self.pending.remove(&key) {
+                        complete.push((pending.msg, pending.peer));
+                    }
+                }
+                return complete;
+            }
+            /*
+             * A continuation without anything to continue, such as when its first line was
+             * already flushed, is treated as the start of a new message
+             */
+        }
+
+        if let Some(previous) = self.pending.remove(&key) {
+            complete.push((previous.msg, previous.peer));
+        }
+
+        if self.conf.max_lines > 1 {
+            self.pending.insert(
+                key,
+                Pending {
+                    msg,
+                    peer,
+                    lines: 1,
+                    deadline: now + timeout,
+                },
+            );
+        } else {
+            complete.push((msg, peer));
+        }
+        complete
+    }
+
+    /**
+     * The earliest time at which a pending message will time out, if there are any
+     */
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.deadline).min()
+    }
+
+    /**
+     * Return the pending messages which have timed out by `now`
+     */
+    pub fn flush_expired(&mut self, now: Instant) -> Vec<Complete> {
+        let expired: Vec<Key> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        self.remove_in_order(expired)
+    }
+
+    /**
+     * Return every pending message, such as when the connection is closing
+     */
+    pub fn flush(&mut self) -> Vec<Complete> {
+        let keys: Vec<Key> = self.pending.keys().cloned().collect();
+        self.remove_in_order(keys)
+    }
+
+    /**
+     * Remove the pending messages for the keys, returning them in the order they timeout
+     */
+    fn remove_in_order(&mut self, keys: Vec<Key>) -> Vec<Complete> {
+        let mut removed: Vec<Pending> = keys
+            .iter()
+            .filter_map(|key| self.pending.remove(key))
+            .collect();
+        removed.sort_by_key(|pending| pending.deadline);
+        removed
+            .into_iter()
+            .map(|pending| (pending.msg, pending.peer))
+            .collect()
+    }
+
+    /**
+     * A line continues the previous message if it matches `continuation`, or when only `start` is
+     * configured, if it doesn't match `start`
+     */
+    fn is_continuation(&self, line: &str) -> bool {
+        if let Some(start) = &self.conf.start {
+            if start.is_match(line) {
+                return false;
+            }
+        }
+
+        match &self.conf.continuation {
+            Some(continuation) => continuation.is_match(line),
+            None => self.conf.start.is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn conf(start: Option<&str>, continuation: Option<&str>, max_lines: usize) -> Multiline {
+        Multiline {
+            start: start.map(|s| Regex::new(s).expect("Invalid regex")),
+            continuation: continuation.map(|c| Regex::new(c).expect("Invalid regex")),
+            max_lines,
+            timeout_ms: 1000,
+        }
+    }
+
+    fn message(appname: &str, msg: &str) -> SyslogMessage {
+        SyslogMessage {
+            msg: msg.to_string(),
+            hostname: Some("coconut".to_string()),
+            appname: Some(appname.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn msgs(messages: Vec<Complete>) -> Vec<String> {
+        messages.into_iter().map(|(m, _)| m.msg).collect()
+    }
+
+    #[test]
+    fn test_continuation_regex() {
+        let mut aggregator = Aggregator::new(&conf(None, Some(r"^\s+at "), 100));
+        let now = Instant::now();
+
+        assert!(aggregator
+            .push(message("java", "Exception in thread main"), None, now)
+            .is_empty());
+        assert!(aggregator
+            .push(message("java", "    at Main.main(Main.java:1)"), None, now)
+            .is_empty());
+        assert_eq!(
+            vec!["Exception in thread main\n    at Main.main(Main.java:1)"],
+            msgs(aggregator.push(message("java", "next"), None, now))
+        );
+        assert_eq!(vec!["next"], msgs(aggregator.flush()));
+    }
+
+    #[test]
+    fn test_start_regex() {
+        let mut aggregator = Aggregator::new(&conf(Some(r"^\d{4}-"), None, 100));
+        let now = Instant::now();
+
+        aggregator.push(message("python", "2020-04-18 Traceback:"), None, now);
+        aggregator.push(message("python", "  File \"main.py\""), None, now);
+        assert_eq!(
+            vec!["2020-04-18 Traceback:\n  File \"main.py\""],
+            msgs(aggregator.push(message("python", "2020-04-18 ok"), None, now))
+        );
+    }
+
+    #[test]
+    fn test_keys_are_separate() {
+        let mut aggregator = Aggregator::new(&conf(None, Some(r"^\s"), 100));
+        let now = Instant::now();
+
+        aggregator.push(message("java", "first"), None, now);
+        aggregator.push(message("python", "second"), None, now);
+        aggregator.push(message("java", " more first"), None, now);
+
+        let mut flushed = msgs(aggregator.flush());
+        flushed.sort();
+        assert_eq!(vec!["first\n more first", "second"], flushed);
+    }
+
+    #[test]
+    fn test_max_lines() {
+        let mut aggregator = Aggregator::new(&conf(None, Some(r"^\s"), 2));
+        let now = Instant::now();
+
+        aggregator.push(message("java", "first"), None, now);
+        assert_eq!(
+            vec!["first\n second"],
+            msgs(aggregator.push(message("java", " second"), None, now))
+        );
+        assert!(aggregator.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_flush_expired() {
+        let mut aggregator = Aggregator::new(&conf(None, Some(r"^\s"), 100));
+        let now = Instant::now();
+
+        aggregator.push(message("java", "first"), None, now);
+        aggregator.push(
+            message("python", "second"),
+            None,
+            now + Duration::from_millis(500),
+        );
+        assert_eq!(
+            Some(now + Duration::from_millis(1000)),
+            aggregator.next_deadline()
+        );
+
+        assert_eq!(
+            vec!["first"],
+            msgs(aggregator.flush_expired(now + Duration::from_millis(1000)))
+        );
+        assert_eq!(vec!["second"], msgs(aggregator.flush()));
+    }
+
+    #[test]
+    fn test_without_patterns() {
+        assert!(conf(None, None, 100).validate().is_err());
+    }
+
+    #[test]
+    fn test_single_line() {
+        let mut aggregator = Aggregator::new(&conf(None, Some(r"^\s"), 1));
+        let now = Instant::now();
+
+        assert_eq!(
+            vec!["first"],
+            msgs(aggregator.push(message("java", "first"), None, now))
+        );
+        assert!(aggregator.next_deadline().is_none());
+    }
+}
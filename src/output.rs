@@ -0,0 +1,405 @@
+use crate::errors::HotdogError;
+use crate::kafka::{KafkaMessage, KafkaSender};
+use crate::output_file::FileOutput;
+use crate::output_http::HttpOutput;
+use crate::output_syslog::SyslogOutput;
+use crate::parse::SyslogMessage;
+use crate::rules::Variables;
+use crate::settings;
+use crate::status::{Statistic, Stats};
+/**
+ * The output module contains the sinks which rules can send messages to, along with the default
+ * Kafka producer used by the forward action.
+ *
+ * Outputs are configured by name in the `global.outputs` section and are created once at startup,
+ * so changes to that section require hotdog to be restarted.
+ */
+use async_std::{io, prelude::*, sync::Sender};
+use async_trait::async_trait;
+use handlebars::Handlebars;
+use log::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/**
+ * OutputMessage carries the message to be sent along with the variables from the rule which
+ * matched it, allowing outputs to render their own templates, and the originally parsed syslog
+ * message
+ */
+#[derive(Debug)]
+pub struct OutputMessage {
+    pub msg: String,
+    pub variables: Variables,
+    pub syslog: SyslogMessage,
+}
+
+/**
+ * An Output is a destination that messages can be sent to from rules
+ */
+#[async_trait]
+pub trait Output: Send + Sync {
+    async fn send(&self, message: &OutputMessage) -> Result<(), HotdogError>;
+
+    /**
+     * Outputs which only queue the message in `send` return true and record OutputSent themselves
+     * once the message has actually been delivered
+     */
+    fn records_delivery(&self) -> bool {
+        false
+    }
+}
+
+/**
+ * Outputs holds all the configured outputs by name, along with the default Kafka producer
+ */
+pub struct Outputs {
+    outputs: HashMap<String, Box<dyn Output>>,
+    /**
+     * The default Kafka producer used by the forward action, which every kafka output shares
+     */
+    kafka: KafkaSender,
+    stats: Sender<Statistic>,
+}
+
+impl Outputs {
+    /**
+     * Create every output defined in the configuration.
+     *
+     * Outputs which send to Kafka will share the given sender
+     */
+    pub fn new(
+        conf: &HashMap<String, settings::Output>,
+        kafka: KafkaSender,
+        stats: Sender<Statistic>,
+    ) -> Result<Outputs, HotdogError> {
+        let mut outputs: HashMap<String, Box<dyn Output>> = HashMap::new();
+
+        for (name, output) in conf.iter() {
+            debug!("Creating the output `{}`", name);
+
+            let created: Box<dyn Output> = match output {
+                settings::Output::Stdout => Box::new(StdoutOutput {}),
+                settings::Output::Kafka { topic, key } => Box::new(KafkaOutput::new(
+                    kafka.clone(),
+                    topic.to_string(),
+                    key.clone(),
+                )?),
+                settings::Output::File {
+                    path,
+                    rotate_bytes,
+                    rotate_secs,
+                    gzip,
+                } => Box::new(FileOutput::new(
+                    path.to_string(),
+                    *rotate_bytes,
+                    rotate_secs.map(Duration::from_secs),
+                    *gzip,
+                )),
+                settings::Output::Syslog {
+                    address,
+                    protocol,
+                    tls,
+                    format,
+                    framing,
+                } => Box::new(SyslogOutput::new(
+                    name,
+                    address.to_string(),
+                    *protocol,
+                    *tls,
+                    *format,
+                    *framing,
+                    stats.clone(),
+                )),
+                settings::Output::Http(http) => {
+                    Box::new(HttpOutput::new(name, http, stats.clone())?)
+                }
+            };
+            outputs.insert(name.to_string(), created);
+        }
+
+        Ok(Outputs {
+            outputs,
+            kafka,
+            stats,
+        })
+    }
+
+    /**
+     * Send the message to the default Kafka producer, as the forward action does
+     */
+    pub async fn forward(&self, kmsg: KafkaMessage) {
+        self.kafka.send(kmsg).await;
+    }
+
+    /**
+     * Send the message to the named output, recording whether it succeeded
+     */
+    pub async fn send(&self, name: &str, message: &OutputMessage) {
+        let (result, records_delivery) = match self.outputs.get(name) {
+            Some(output) => (output.send(message).await, output.records_delivery()),
+            None => (
+                Err(HotdogError::OutputError {
+                    err: format!("No output named `{}` has been configured", name),
+                }),
+                false,
+            ),
+        };
+
+        match result {
+            Ok(_) if records_delivery => {}
+            Ok(_) => {
+                self.stats
+                    .send((
+                        Stats::OutputSent {
+                            name: name.to_string(),
+                        },
+                        1,
+                    ))
+                    .await;
+            }
+            Err(e) => {
+                error!("Failed to send to the output `{}`: {:?}", name, e);
+                self.stats
+                    .send((
+                        Stats::OutputErrored {
+                            name: name.to_string(),
+                        },
+                        1,
+                    ))
+                    .await;
+            }
+        }
+    }
+}
+
+/**
+ * StdoutOutput writes each message as a line to stdout, which is mostly useful for debugging
+ */
+pub struct StdoutOutput {}
+
+#[async_trait]
+impl Output for StdoutOutput {
+    async fn send(&self, message: &OutputMessage) -> Result<(), HotdogError> {
+        let mut stdout = io::stdout();
+        stdout
+            .write_all(format!("{}\n", message.msg).as_bytes())
+            .await?;
+        Ok(())
+    }
+}
+
+/**
+ * KafkaOutput sends messages along to Kafka through the shared producer
+ */
+pub struct KafkaOutput {
+    sender: KafkaSender,
+    has_key: bool,
+    /**
+     * The topic and key templates, which are compiled once when the output is created
+     */
+    hb: Handlebars<'static>,
+}
+
+const TOPIC_TEMPLATE: &str = "topic";
+const KEY_TEMPLATE: &str = "key";
+
+impl KafkaOutput {
+    pub fn new(
+        sender: KafkaSender,
+        topic: String,
+        key: Option<String>,
+    ) -> Result<Self, HotdogError> {
+        let mut hb = Handlebars::new();
+        /*
+         * Kafka topics and keys are not HTML
+         */
+        hb.register_escape_fn(handlebars::no_escape);
+
+        hb.register_template_string(TOPIC_TEMPLATE, &topic)
+            .map_err(|e| HotdogError::OutputError {
+                err: format!("Invalid topic `{}`: {:?}", topic, e),
+            })?;
+
+        if let Some(key) = &key {
+            hb.register_template_string(KEY_TEMPLATE, key)
+                .map_err(|e| HotdogError::OutputError {
+                    err: format!("Invalid key `{}`: {:?}", key, e),
+                })?;
+        }
+
+        Ok(KafkaOutput {
+            sender,
+            has_key: key.is_some(),
+            hb,
+        })
+    }
+}
+
+#[async_trait]
+impl Output for KafkaOutput {
+    async fn send(&self, message: &OutputMessage) -> Result<(), HotdogError> {
+        let topic = self
+            .hb
+            .render(TOPIC_TEMPLATE, &message.variables)
+            .map_err(|e| HotdogError::OutputError {
+                err: format!("Failed to render the topic: {:?}", e),
+            })?;
+
+        let mut kmsg = KafkaMessage::new(topic, message.msg.clone());
+
+        if self.has_key {
+            let key = self
+                .hb
+                .render(KEY_TEMPLATE, &message.variables)
+                .map_err(|e| HotdogError::OutputError {
+                    err: format!("Failed to render the key: {:?}", e),
+                })?;
+            kmsg.set_key(key);
+        }
+
+        self.sender.send(kmsg).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::sync::channel;
+    use async_std::task;
+
+    fn message(msg: &str) -> OutputMessage {
+        let mut variables = Variables::new();
+        variables.insert("appname".to_string(), "tyler".into());
+        OutputMessage {
+            msg: msg.to_string(),
+            variables,
+            syslog: SyslogMessage {
+                msg: msg.to_string(),
+                appname: Some("tyler".to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_kafka_output() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let output = KafkaOutput::new(
+                KafkaSender::new(sender, None),
+                "logs-{{appname}}".to_string(),
+                Some("{{appname}}".to_string()),
+            )
+            .expect("Failed to create the output");
+
+            output
+                .send(&message("hello"))
+                .await
+                .expect("Failed to send");
+
+            let mut expected = KafkaMessage::new("logs-tyler".to_string(), "hello".to_string());
+            expected.set_key("tyler".to_string());
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(expected, kmsg);
+        });
+    }
+
+    #[test]
+    fn test_kafka_output_does_not_escape() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let output = KafkaOutput::new(
+                KafkaSender::new(sender, None),
+                "logs-{{appname}}".to_string(),
+                None,
+            )
+            .expect("Failed to create the output");
+
+            let mut msg = message("hello");
+            msg.variables
+                .insert("appname".to_string(), "tyler&co".into());
+            output.send(&msg).await.expect("Failed to send");
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("logs-tyler&co".to_string(), "hello".to_string()),
+                kmsg
+            );
+        });
+    }
+
+    #[test]
+    fn test_kafka_output_invalid_template() {
+        let (sender, _receiver) = channel(1);
+        assert!(KafkaOutput::new(
+            KafkaSender::new(sender, None),
+            "logs-{{appname".to_string(),
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_send_to_missing_output() {
+        task::block_on(async {
+            let (sender, _receiver) = channel(1);
+            let (stats, stats_receiver) = channel(1);
+            let outputs = Outputs::new(&HashMap::new(), KafkaSender::new(sender, None), stats)
+                .expect("Failed to create the outputs");
+
+            outputs.send("missing", &message("hello")).await;
+
+            let (stat, count) = stats_receiver.recv().await.expect("No stat was recorded");
+            assert_eq!(
+                Stats::OutputErrored {
+                    name: "missing".to_string()
+                },
+                stat
+            );
+            assert_eq!(1, count);
+        });
+    }
+
+    #[test]
+    fn test_forward() {
+        task::block_on(async {
+            let (sender, receiver) = channel(1);
+            let (stats, _) = channel(1);
+            let outputs = Outputs::new(&HashMap::new(), KafkaSender::new(sender, None), stats)
+                .expect("Failed to create the outputs");
+
+            outputs
+                .forward(KafkaMessage::new("logs".to_string(), "hello".to_string()))
+                .await;
+
+            let kmsg = receiver.recv().await.expect("Failed to receive a message");
+            assert_eq!(
+                KafkaMessage::new("logs".to_string(), "hello".to_string()),
+                kmsg
+            );
+        });
+    }
+
+    #[test]
+    fn test_send_to_stdout() {
+        task::block_on(async {
+            let (sender, _receiver) = channel(1);
+            let (stats, stats_receiver) = channel(1);
+            let mut conf = HashMap::new();
+            conf.insert("debug".to_string(), settings::Output::Stdout);
+            let outputs = Outputs::new(&conf, KafkaSender::new(sender, None), stats)
+                .expect("Failed to create the outputs");
+
+            outputs.send("debug", &message("hello")).await;
+
+            let (stat, _) = stats_receiver.recv().await.expect("No stat was recorded");
+            assert_eq!(
+                Stats::OutputSent {
+                    name: "debug".to_string()
+                },
+                stat
+            );
+        });
+    }
+}
@@ -0,0 +1,402 @@
+use crate::errors::HotdogError;
+use crate::output::{Output, OutputMessage};
+use crate::rules::Variables;
+/**
+ * The output_file module contains the output which writes messages to files on the local
+ * filesystem, rotating them by size or age
+ */
+use async_std::{sync::Arc, task};
+use async_trait::async_trait;
+use chrono::prelude::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use handlebars::Handlebars;
+use log::*;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/**
+ * Files which haven't been written to in this long will be closed, which prevents file handles
+ * from accumulating when the path template includes things like the date
+ */
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+struct OpenFile {
+    /**
+     * The handle is closed once the file has been idle, but the rest is kept so that the file
+     * is still rotated by its age when it is reopened
+     */
+    file: Option<File>,
+    bytes: u64,
+    created: SystemTime,
+    written: Instant,
+}
+
+/**
+ * The files being written to, which are only touched from blocking threads since writing to them
+ * blocks
+ */
+struct Files {
+    rotate_bytes: Option<u64>,
+    rotate_after: Option<Duration>,
+    gzip: bool,
+    open: HashMap<PathBuf, OpenFile>,
+}
+
+pub struct FileOutput {
+    path: String,
+    hb: Handlebars<'static>,
+    files: Arc<Mutex<Files>>,
+}
+
+impl FileOutput {
+    pub fn new(
+        path: String,
+        rotate_bytes: Option<u64>,
+        rotate_after: Option<Duration>,
+        gzip: bool,
+    ) -> Self {
+        let mut hb = Handlebars::new();
+        /*
+         * HTML escaping makes no sense for file paths
+         */
+        hb.register_escape_fn(handlebars::no_escape);
+
+        FileOutput {
+            path,
+            hb,
+            files: Arc::new(Mutex::new(Files {
+                rotate_bytes,
+                rotate_after,
+                gzip,
+                open: HashMap::new(),
+            })),
+        }
+    }
+
+    /**
+     * Render the path for the message, refusing paths which could escape the configured directory
+     * through variables such as `..`
+     */
+    fn render_path(&self, variables: &Variables) -> Result<PathBuf, HotdogError> {
+        let rendered = self
+            .hb
+            .render_template(&self.path, variables)
+            .map_err(|e| HotdogError::OutputError {
+                err: format!("Failed to render the path: {:?}", e),
+            })?;
+        let path = PathBuf::from(rendered);
+
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Err(HotdogError::OutputError {
+                err: format!("Refusing to write to the path {}", path.display()),
+            });
+        }
+        Ok(path)
+    }
+}
+
+impl Files {
+    /**
+     * Append the line to the file, rotating it first if necessary
+     */
+    fn write(&mut self, path: PathBuf, line: &str) -> io::Result<()> {
+        let (rotate_bytes, rotate_after) = (self.rotate_bytes, self.rotate_after);
+        let files = &mut self.open;
+        let bytes = line.len() as u64;
+
+        let needs_rotation = files.get(&path).map_or(false, |open| {
+            rotate_bytes.map_or(false, |max| open.bytes > 0 && open.bytes + bytes > max)
+                || rotate_after.map_or(false, |age| {
+                    SystemTime::now()
+                        .duration_since(open.created)
+                        .map_or(false, |elapsed| elapsed >= age)
+                })
+        });
+
+        if needs_rotation {
+            files.remove(&path);
+            rotate(&path, self.gzip)?;
+        }
+
+        match files.get_mut(&path) {
+            Some(open) if open.file.is_none() => {
+                let (file, bytes) = open_append(&path)?;
+                open.file = Some(file);
+                open.bytes = bytes;
+            }
+            Some(_) => {}
+            None => {
+                files.insert(path.clone(), open(&path)?);
+            }
+        }
+
+        if let Some(open) = files.get_mut(&path) {
+            if let Some(file) = open.file.as_mut() {
+                file.write_all(line.as_bytes())?;
+            }
+            open.bytes += bytes;
+            open.written = Instant::now();
+        }
+
+        /*
+         * Closed files are only remembered for as long as their age could still matter
+         */
+        files.retain(|_, open| {
+            let idle = open.written.elapsed();
+            if idle >= IDLE_TIMEOUT {
+                open.file = None;
+            }
+            open.file.is_some() || rotate_after.map_or(false, |age| idle < age)
+        });
+        Ok(())
+    }
+}
+
+/**
+ * Move the current file out of the way, compressing it in the background if configured
+ */
+fn rotate(path: &Path, gzip: bool) -> io::Result<()> {
+    let rotated = rotated_path(path);
+    debug!("Rotating {} to {}", path.display(), rotated.display());
+    std::fs::rename(path, &rotated)?;
+
+    if gzip {
+        std::thread::spawn(move || {
+            if let Err(e) = compress(&rotated) {
+                error!("Failed to compress {}: {:?}", rotated.display(), e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Output for FileOutput {
+    async fn send(&self, message: &OutputMessage) -> Result<(), HotdogError> {
+        let path = self.render_path(&message.variables)?;
+        let line = format!("{}\n", message.msg);
+        let files = self.files.clone();
+
+        task::spawn_blocking(move || files.lock().write(path, &line)).await?;
+        Ok(())
+    }
+}
+
+/**
+ * Open the file for appending, creating its directory if necessary.
+ *
+ * The age of a file which already exists is measured from when it was created, or when the
+ * filesystem doesn't record that, from now
+ */
+fn open(path: &Path) -> io::Result<OpenFile> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let (file, bytes) = open_append(path)?;
+    let created = file
+        .metadata()?
+        .created()
+        .unwrap_or_else(|_| SystemTime::now());
+
+    Ok(OpenFile {
+        file: Some(file),
+        bytes,
+        created,
+        written: Instant::now(),
+    })
+}
+
+/**
+ * Open the file for appending, returning it along with its current size
+ */
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let bytes = file.metadata()?.len();
+    Ok((file, bytes))
+}
+
+/**
+ * Generate a unique path for the rotated file, e.g. `app.log.20200418T151609`
+ */
+fn rotated_path(path: &Path) -> PathBuf {
+    let base = format!("{}.{}", path.display(), Utc::now().format("%Y%m%dT%H%M%S"));
+    let mut rotated = PathBuf::from(&base);
+    let mut count = 1;
+
+    while rotated.exists() || PathBuf::from(format!("{}.gz", rotated.display())).exists() {
+        rotated = PathBuf::from(format!("{}.{}", base, count));
+        count += 1;
+    }
+    rotated
+}
+
+/**
+ * Compress the file with gzip, removing the original once it has been compressed
+ */
+fn compress(path: &Path) -> io::Result<()> {
+    let compressed = PathBuf::from(format!("{}.gz", path.display()));
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    std::fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::SyslogMessage;
+    use async_std::task;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("hotdog-file-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn message(msg: &str) -> OutputMessage {
+        let mut variables = Variables::new();
+        variables.insert("appname".to_string(), "tyler".into());
+        OutputMessage {
+            msg: msg.to_string(),
+            variables,
+            syslog: SyslogMessage {
+                msg: msg.to_string(),
+                appname: Some("tyler".to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn files_in(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .expect("Failed to read the directory")
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_write_templated_path() {
+        task::block_on(async {
+            let dir = temp_dir();
+            let output = FileOutput::new(
+                format!("{}/{{{{appname}}}}/out.log", dir.display()),
+                None,
+                None,
+                false,
+            );
+
+            output
+                .send(&message("hello"))
+                .await
+                .expect("Failed to send");
+            output
+                .send(&message("world"))
+                .await
+                .expect("Failed to send");
+
+            let contents = std::fs::read_to_string(dir.join("tyler/out.log"))
+                .expect("Failed to read the output");
+            assert_eq!("hello\nworld\n", contents);
+
+            std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+        });
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        task::block_on(async {
+            let dir = temp_dir();
+            let output =
+                FileOutput::new(format!("{}/out.log", dir.display()), Some(8), None, false);
+
+            output
+                .send(&message("hello"))
+                .await
+                .expect("Failed to send");
+            output
+                .send(&message("world"))
+                .await
+                .expect("Failed to send");
+
+            let names = files_in(&dir);
+            assert_eq!(2, names.len());
+            assert_eq!("out.log", names[0]);
+            assert!(names[1].starts_with("out.log."));
+            assert_eq!(
+                "world\n",
+                std::fs::read_to_string(dir.join("out.log")).expect("Failed to read the output")
+            );
+
+            std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+        });
+    }
+
+    #[test]
+    fn test_rotate_by_age_after_reopen() {
+        task::block_on(async {
+            let dir = temp_dir();
+            let output = FileOutput::new(
+                format!("{}/out.log", dir.display()),
+                None,
+                Some(Duration::from_millis(200)),
+                false,
+            );
+
+            output
+                .send(&message("hello"))
+                .await
+                .expect("Failed to send");
+
+            /*
+             * Close the handle as though the file had been idle
+             */
+            for open in output.files.lock().open.values_mut() {
+                open.file = None;
+            }
+            task::sleep(Duration::from_millis(300)).await;
+
+            output
+                .send(&message("world"))
+                .await
+                .expect("Failed to send");
+
+            let names = files_in(&dir);
+            assert_eq!(2, names.len());
+            assert!(names[1].starts_with("out.log."));
+            assert_eq!(
+                "world\n",
+                std::fs::read_to_string(dir.join("out.log")).expect("Failed to read the output")
+            );
+
+            std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+        });
+    }
+
+    #[test]
+    fn test_compress() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(&dir).expect("Failed to create the directory");
+        let path = dir.join("out.log.1");
+        std::fs::write(&path, "hello\n").expect("Failed to write");
+
+        compress(&path).expect("Failed to compress");
+
+        assert_eq!(vec!["out.log.1.gz"], files_in(&dir));
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_refuse_parent_dir() {
+        let output = FileOutput::new("/tmp/{{appname}}/out.log".to_string(), None, None, false);
+        let mut variables = Variables::new();
+        variables.insert("appname".to_string(), "../etc".into());
+        assert!(output.render_path(&variables).is_err());
+    }
+}
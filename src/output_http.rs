@@ -0,0 +1,464 @@
+use crate::errors::HotdogError;
+use crate::output::{Output, OutputMessage};
+use crate::settings::{self, HttpAuth};
+use crate::status::{Statistic, Stats};
+/**
+ * The output_http module contains the output which posts batches of messages to an HTTP endpoint,
+ * such as an Elasticsearch `_bulk` or Loki-style push endpoint.
+ *
+ * Messages are queued by send() and delivered by a background task, with each batch sent as a
+ * single request whose body contains the messages separated by newlines.
+ */
+use async_std::{
+    future,
+    net::TcpStream,
+    sync::{channel, Receiver, Sender},
+    task,
+};
+use async_tls::TlsConnector;
+use async_trait::async_trait;
+use http_types::{Method, Request, Response, Url};
+use log::*;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/**
+ * The initial delay before retrying a failed request, which doubles with every retry
+ */
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/**
+ * How many batches worth of messages can be queued before messages are dropped
+ */
+const QUEUED_BATCHES: usize = 4;
+
+pub struct HttpOutput {
+    tx: Sender<String>,
+}
+
+impl HttpOutput {
+    pub fn new(
+        name: &str,
+        conf: &settings::Http,
+        stats: Sender<Statistic>,
+    ) -> Result<Self, HotdogError> {
+        let url = Url::parse(&conf.url).map_err(|e| HotdogError::OutputError {
+            err: format!("Invalid url `{}`: {:?}", conf.url, e),
+        })?;
+        let method = Method::from_str(&conf.method.to_uppercase()).map_err(|e| {
+            HotdogError::OutputError {
+                err: format!("Invalid method `{}`: {:?}", conf.method, e),
+            }
+        })?;
+
+        let mut headers: Vec<(String, String)> = conf
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        if let Some(auth) = &conf.auth {
+            headers.push(("Authorization".to_string(), authorization(auth)));
+        }
+
+        let batch_size = std::cmp::max(conf.batch_size, 1);
+        let (tx, rx) = channel(batch_size * QUEUED_BATCHES);
+
+        let client = HttpClient {
+            name: name.to_string(),
+            url,
+            method,
+            headers,
+            retries: conf.retries,
+            timeout: Duration::from_millis(conf.timeout_ms),
+            stats,
+        };
+        let interval = Duration::from_millis(conf.batch_ms);
+
+        task::spawn(async move {
+            batchloop(client, rx, batch_size, interval).await;
+        });
+
+        Ok(HttpOutput { tx })
+    }
+}
+
+#[async_trait]
+impl Output for HttpOutput {
+    async fn send(&self, message: &OutputMessage) -> Result<(), HotdogError> {
+        /*
+         * Rather than letting a slow endpoint back up every listener, drop the message when too
+         * much is already waiting to be sent
+         */
+        if self.tx.is_full() {
+            return Err(HotdogError::OutputError {
+                err: "Too many messages are waiting to be sent".to_string(),
+            });
+        }
+        self.tx.send(message.msg.clone()).await;
+        Ok(())
+    }
+}
+
+/**
+ * HttpClient carries everything needed to deliver batches to the endpoint
+ */
+struct HttpClient {
+    name: String,
+    url: Url,
+    method: Method,
+    headers: Vec<(String, String)>,
+    retries: u32,
+    timeout: Duration,
+    stats: Sender<Statistic>,
+}
+
+impl HttpClient {
+    /**
+     * Deliver the batch, retrying connection failures, timeouts, and server errors with a backoff
+     */
+    async fn deliver(&self, batch: Vec<String>) {
+        let count = batch.len() as i64;
+        let mut body = batch.join("\n");
+        body.push('\n');
+
+        let mut backoff = INITIAL_RETRY_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            let retriable = match future::timeout(self.timeout, self.request(&body)).await {
+                Ok(Ok(response)) if response.status().is_success() => {
+                    debug!("Delivered {} messages to {}", count, self.url);
+                    self.stats
+                        .send((
+                            Stats::HttpMsgSent {
+                                name: self.name.clone(),
+                            },
+                            count,
+                        ))
+                        .await;
+                    return;
+                }
+                Ok(Ok(response)) => {
+                    warn!(
+                        "{} responded with {} for {} messages",
+                        self.url,
+                        response.status(),
+                        count
+                    );
+                    response.status().is_server_error()
+                }
+                Ok(Err(e)) => {
+                    warn!("Failed to send {} messages to {}: {:?}", count, self.url, e);
+                    true
+                }
+                Err(_) => {
+                    warn!(
+                        "Timed out after {:?} sending {} messages to {}",
+                        self.timeout, count, self.url
+                    );
+                    true
+                }
+            };
+
+            if !retriable || attempt >= self.retries {
+                break;
+            }
+            attempt += 1;
+            task::sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        error!("Dropping {} messages for {}", count, self.url);
+        self.stats
+            .send((
+                Stats::HttpMsgFailed {
+                    name: self.name.clone(),
+                },
+                count,
+            ))
+            .await;
+    }
+
+    async fn request(&self, body: &str) -> http_types::Result<Response> {
+        let mut req = Request::new(self.method, self.url.clone());
+        for (name, value) in self.headers.iter() {
+            req.insert_header(name.as_str(), value.as_str());
+        }
+        req.set_body(body);
+
+        let host = self.url.host_str().unwrap_or("localhost");
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        let stream = TcpStream::connect((host, port)).await?;
+
+        if self.url.scheme() == "https" {
+            let stream = TlsConnector::default().connect(host, stream).await?;
+            async_h1::connect(stream, req).await
+        } else {
+            async_h1::connect(stream, req).await
+        }
+    }
+}
+
+/**
+ * batchloop collects messages until the batch is full, or the interval has passed since the first
+ * message of the batch arrived, and then delivers them
+ */
+async fn batchloop(
+    client: HttpClient,
+    rx: Receiver<String>,
+    batch_size: usize,
+    interval: Duration,
+) {
+    while let Ok(first) = rx.recv().await {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + interval;
+
+        while batch.len() < batch_size {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            match future::timeout(remaining, rx.recv()).await {
+                Ok(Ok(msg)) => batch.push(msg),
+                _ => break,
+            }
+        }
+
+        client.deliver(batch).await;
+    }
+}
+
+/**
+ * Generate the Authorization header value for the configured authentication
+ */
+fn authorization(auth: &HttpAuth) -> String {
+    match auth {
+        HttpAuth::Basic { username, password } => format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", username, password))
+        ),
+        HttpAuth::Bearer { token } => format!("Bearer {}", token),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::SyslogMessage;
+    use crate::rules::Variables;
+    use async_std::sync::{Arc, Mutex};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /**
+     * Requests received by the stand-in server, as (Authorization header, body)
+     */
+    type Received = Arc<Mutex<Vec<(String, String)>>>;
+
+    #[derive(Clone)]
+    struct StandIn {
+        received: Received,
+        /**
+         * The number of requests which should fail with a 503 before succeeding
+         */
+        failures: Arc<AtomicUsize>,
+    }
+
+    /**
+     * Launch a stand-in for an HTTP collector, returning its url
+     */
+    fn stand_in(failures: usize) -> (String, Received) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .expect("Failed to find a free port")
+            .local_addr()
+            .expect("No local address")
+            .port();
+        let received: Received = Arc::new(Mutex::new(vec![]));
+
+        let mut app = tide::with_state(StandIn {
+            received: received.clone(),
+            failures: Arc::new(AtomicUsize::new(failures)),
+        });
+        app.at("/bulk")
+            .post(|mut req: tide::Request<StandIn>| async move {
+                let state = req.state().clone();
+                if state.failures.load(Ordering::SeqCst) > 0 {
+                    state.failures.fetch_sub(1, Ordering::SeqCst);
+                    return Ok(tide::Response::new(tide::StatusCode::ServiceUnavailable));
+                }
+
+                let auth = req
+                    .header("Authorization")
+                    .map(|values| values.last().as_str().to_string())
+                    .unwrap_or_default();
+                let body = req.body_string().await?;
+                state.received.lock().await.push((auth, body));
+                Ok(tide::Response::new(tide::StatusCode::Ok))
+            });
+
+        let addr = format!("127.0.0.1:{}", port);
+        task::spawn(async move { app.listen(addr).await });
+
+        (format!("http://127.0.0.1:{}/bulk", port), received)
+    }
+
+    fn conf(url: String, batch_size: usize) -> settings::Http {
+        settings::Http {
+            url,
+            method: "post".to_string(),
+            headers: HashMap::new(),
+            auth: Some(HttpAuth::Bearer {
+                token: "sekrit".to_string(),
+            }),
+            batch_size,
+            batch_ms: 100,
+            retries: 5,
+            timeout_ms: 1000,
+        }
+    }
+
+    fn message(msg: &str) -> OutputMessage {
+        OutputMessage {
+            msg: msg.to_string(),
+            variables: Variables::new(),
+            syslog: SyslogMessage {
+                msg: msg.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /**
+     * Wait for the stand-in server to have received a request
+     */
+    async fn wait_for(received: &Received) -> Vec<(String, String)> {
+        for _ in 0..100 {
+            {
+                let received = received.lock().await;
+                if !received.is_empty() {
+                    return received.clone();
+                }
+            }
+            task::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("The stand-in server never received a request");
+    }
+
+    #[test]
+    fn test_authorization_basic() {
+        let auth = HttpAuth::Basic {
+            username: "tyler".to_string(),
+            password: "hotdog".to_string(),
+        };
+        assert_eq!("Basic dHlsZXI6aG90ZG9n", authorization(&auth));
+    }
+
+    #[test]
+    fn test_batching() {
+        task::block_on(async {
+            let (url, received) = stand_in(0);
+            let (stats, stats_receiver) = channel(10);
+            let output = HttpOutput::new("collector", &conf(url, 2), stats)
+                .expect("Failed to create the output");
+
+            output
+                .send(&message("hello"))
+                .await
+                .expect("Failed to send");
+            output
+                .send(&message("world"))
+                .await
+                .expect("Failed to send");
+
+            let received = wait_for(&received).await;
+            assert_eq!(
+                vec![("Bearer sekrit".to_string(), "hello\nworld\n".to_string())],
+                received
+            );
+            assert_eq!(
+                Ok((
+                    Stats::HttpMsgSent {
+                        name: "collector".to_string()
+                    },
+                    2
+                )),
+                stats_receiver.recv().await.map_err(|_| ())
+            );
+        });
+    }
+
+    #[test]
+    fn test_retry_on_server_error() {
+        task::block_on(async {
+            let (url, received) = stand_in(2);
+            let (stats, _stats_receiver) = channel(10);
+            let output = HttpOutput::new("collector", &conf(url, 10), stats)
+                .expect("Failed to create the output");
+
+            output
+                .send(&message("hello"))
+                .await
+                .expect("Failed to send");
+
+            let received = wait_for(&received).await;
+            assert_eq!(1, received.len());
+            assert_eq!("hello\n", received[0].1);
+        });
+    }
+
+    /**
+     * An endpoint which accepts connections but never responds should be retried, and then
+     * counted as failed
+     */
+    #[test]
+    fn test_timeout_is_retried() {
+        task::block_on(async {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind");
+            let url = format!(
+                "http://{}/bulk",
+                listener.local_addr().expect("No local address")
+            );
+            let accepted = Arc::new(AtomicUsize::new(0));
+            let counter = accepted.clone();
+
+            task::spawn(async move {
+                let mut streams = vec![];
+                while let Ok((stream, _)) = listener.accept().await {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    /* Hold the connection open without ever responding */
+                    streams.push(stream);
+                }
+            });
+
+            let (stats, stats_receiver) = channel(10);
+            let mut http = conf(url, 1);
+            http.retries = 1;
+            http.timeout_ms = 100;
+            let output =
+                HttpOutput::new("collector", &http, stats).expect("Failed to create the output");
+
+            output
+                .send(&message("hello"))
+                .await
+                .expect("Failed to send");
+
+            assert_eq!(
+                Ok((
+                    Stats::HttpMsgFailed {
+                        name: "collector".to_string()
+                    },
+                    1
+                )),
+                stats_receiver.recv().await.map_err(|_| ())
+            );
+            assert_eq!(2, accepted.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn test_invalid_url() {
+        let (stats, _) = channel(1);
+        assert!(HttpOutput::new("collector", &conf("not a url".to_string(), 1), stats).is_err());
+    }
+}
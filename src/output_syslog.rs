@@ -0,0 +1,603 @@
+use crate::errors::HotdogError;
+use crate::output::{Output, OutputMessage};
+use crate::parse::{facility_code, severity_code, StructuredData};
+use crate::settings::{Framing, Protocol, SyslogFormat};
+use crate::status::{Statistic, Stats};
+/**
+ * The output_syslog module contains the output which relays messages on to another syslog server,
+ * such as a downstream SIEM.
+ *
+ * Messages are queued by send() and relayed by a background task, so that a slow or unreachable
+ * server never blocks the connections the messages were received on. Connections are made lazily
+ * and re-established with an exponential backoff when they fail, with messages waiting in the
+ * queue until the server can be reached again.
+ */
+use async_std::{
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    prelude::*,
+    sync::{channel, Receiver, Sender},
+    task,
+};
+use async_tls::TlsConnector;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use log::*;
+use std::time::Duration;
+
+/**
+ * The initial delay before reconnecting, which doubles with every failure
+ */
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/**
+ * How long connecting, including the TLS handshake, or writing a message may take before the
+ * server is considered unreachable
+ */
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * How many messages can wait to be relayed, such as while reconnecting, before messages are
+ * dropped
+ */
+const QUEUED_MESSAGES: usize = 1024;
+
+/**
+ * Facility and severity used when the original message didn't have them, user.notice
+ */
+const DEFAULT_FACILITY: u8 = 1;
+const DEFAULT_SEVERITY: u8 = 5;
+
+enum Transport {
+    Udp(UdpSocket),
+    Stream(Box<dyn Write + Send + Unpin>),
+}
+
+pub struct SyslogOutput {
+    address: String,
+    protocol: Protocol,
+    format: SyslogFormat,
+    framing: Framing,
+    tx: Sender<Vec<u8>>,
+}
+
+impl SyslogOutput {
+    pub fn new(
+        name: &str,
+        address: String,
+        protocol: Protocol,
+        tls: bool,
+        format: SyslogFormat,
+        framing: Framing,
+        stats: Sender<Statistic>,
+    ) -> Self {
+        let (tx, rx) = channel(QUEUED_MESSAGES);
+        let relay = Relay {
+            name: name.to_string(),
+            address: address.clone(),
+            protocol,
+            tls,
+            timeout: IO_TIMEOUT,
+            stats,
+            transport: None,
+            backoff: INITIAL_BACKOFF,
+        };
+
+        task::spawn(async move {
+            relayloop(relay, rx).await;
+        });
+
+        SyslogOutput {
+            address,
+            protocol,
+            format,
+            framing,
+            tx,
+        }
+    }
+
+    /**
+     * Frame the formatted message for the transport in use
+     */
+    fn frame(&self, formatted: String) -> Vec<u8> {
+        if self.protocol == Protocol::Udp {
+            return formatted.into_bytes();
+        }
+
+        let octet_counted = match self.framing {
+            Framing::OctetCounted => true,
+            Framing::NonTransparent => false,
+            /*
+             * RFC 6587 recommends octet-counting, but most RFC 3164 receivers only understand
+             * newline delimited messages
+             */
+            Framing::Auto => self.format == SyslogFormat::Rfc5424,
+        };
+
+        if octet_counted {
+            format!("{} {}", formatted.len(), formatted).into_bytes()
+        } else {
+            format!("{}\n", formatted).into_bytes()
+        }
+    }
+}
+
+#[async_trait]
+impl Output for SyslogOutput {
+    async fn send(&self, message: &OutputMessage) -> Result<(), HotdogError> {
+        /*
+         * Rather than letting an unreachable server back up every listener, drop the message when
+         * too much is already waiting to be relayed
+         */
+        if self.tx.is_full() {
+            return Err(HotdogError::OutputError {
+                err: format!("Too many messages are waiting to relay to {}", self.address),
+            });
+        }
+        let bytes = self.frame(format_message(self.format, message));
+        self.tx.send(bytes).await;
+        Ok(())
+    }
+
+    /**
+     * Messages are only queued by send, the relay records whether they were written
+     */
+    fn records_delivery(&self) -> bool {
+        true
+    }
+}
+
+/**
+ * Relay carries the connection to the syslog server, which is only used by the background task
+ */
+struct Relay {
+    name: String,
+    address: String,
+    protocol: Protocol,
+    tls: bool,
+    timeout: Duration,
+    stats: Sender<Statistic>,
+    transport: Option<Transport>,
+    backoff: Duration,
+}
+
+impl Relay {
+    /**
+     * Relay the framed message, waiting for the server to become reachable if necessary.
+     *
+     * A message which fails to be written is dropped rather than retried, since part of it may
+     * already have been received
+     */
+    async fn relay(&mut self, bytes: &[u8]) {
+        while self.transport.is_none() {
+            match io::timeout(
+                self.timeout,
+                connect(&self.address, self.protocol, self.tls),
+            )
+            .await
+            {
+                Ok(transport) => {
+                    info!("Connected to the syslog server at {}", self.address);
+                    self.transport = Some(transport);
+                    self.backoff = INITIAL_BACKOFF;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to connect to {}, retrying in {:?}: {:?}",
+                        self.address, self.backoff, e
+                    );
+                    task::sleep(self.backoff).await;
+                    self.backoff = std::cmp::min(self.backoff * 2, MAX_BACKOFF);
+                }
+            }
+        }
+
+        let timeout = self.timeout;
+        let result = match self.transport.as_mut() {
+            Some(Transport::Udp(socket)) => {
+                io::timeout(timeout, socket.send(bytes)).await.map(|_| ())
+            }
+            Some(Transport::Stream(stream)) => io::timeout(timeout, stream.write_all(bytes)).await,
+            None => Ok(()),
+        };
+
+        match result {
+            Ok(()) => {
+                self.stats
+                    .send((
+                        Stats::OutputSent {
+                            name: self.name.clone(),
+                        },
+                        1,
+                    ))
+                    .await;
+            }
+            Err(e) => {
+                error!("Failed to relay a message to {}: {:?}", self.address, e);
+                /*
+                 * Drop the connection so the next message will reconnect
+                 */
+                self.transport = None;
+                self.stats
+                    .send((
+                        Stats::OutputErrored {
+                            name: self.name.clone(),
+                        },
+                        1,
+                    ))
+                    .await;
+            }
+        }
+    }
+}
+
+/**
+ * Connect to the syslog server, including the TLS handshake when it is enabled
+ */
+async fn connect(address: &str, protocol: Protocol, tls: bool) -> io::Result<Transport> {
+    debug!("Connecting to the syslog server at {}", address);
+
+    match protocol {
+        Protocol::Udp => {
+            let target = address.to_socket_addrs().await?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "Could not resolve the address")
+            })?;
+            let bind = if target.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(bind).await?;
+            socket.connect(target).await?;
+            Ok(Transport::Udp(socket))
+        }
+        Protocol::Tcp => {
+            let stream = TcpStream::connect(address).await?;
+
+            if tls {
+                let connector = TlsConnector::default();
+                let stream = connector.connect(host_of(address), stream).await?;
+                Ok(Transport::Stream(Box::new(stream)))
+            } else {
+                Ok(Transport::Stream(Box::new(stream)))
+            }
+        }
+    }
+}
+
+/**
+ * relayloop relays every queued message in order, for as long as the output exists
+ */
+async fn relayloop(mut relay: Relay, rx: Receiver<Vec<u8>>) {
+    while let Ok(bytes) = rx.recv().await {
+        relay.relay(&bytes).await;
+    }
+}
+
+/**
+ * Reconstruct the syslog message from the parsed fields, using the output buffer as the MSG
+ */
+fn format_message(format: SyslogFormat, message: &OutputMessage) -> String {
+    let syslog = &message.syslog;
+    let facility = syslog
+        .facility
+        .as_deref()
+        .and_then(facility_code)
+        .unwrap_or(DEFAULT_FACILITY);
+    let severity = syslog
+        .severity
+        .as_deref()
+        .and_then(severity_code)
+        .unwrap_or(DEFAULT_SEVERITY);
+    let pri = facility as u16 * 8 + severity as u16;
+    /*
+     * Preserve the original timestamp when the message had one
+     */
+    let timestamp = syslog
+        .timestamp
+        .unwrap_or_else(|| Utc::now().with_timezone(&FixedOffset::east(0)));
+
+    match format {
+        SyslogFormat::Rfc5424 => format!(
+            "<{}>1 {} {} {} {} {} {} {}",
+            pri,
+            timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+            syslog.hostname.as_deref().unwrap_or("-"),
+            syslog.appname.as_deref().unwrap_or("-"),
+            syslog.procid.as_deref().unwrap_or("-"),
+            syslog.msgid.as_deref().unwrap_or("-"),
+            format_structured_data(&syslog.structured_data),
+            message.msg
+        ),
+        SyslogFormat::Rfc3164 => {
+            let tag = match (&syslog.appname, &syslog.procid) {
+                (Some(appname), Some(procid)) => format!("{}[{}]: ", appname, procid),
+                (Some(appname), None) => format!("{}: ", appname),
+                _ => "".to_string(),
+            };
+            format!(
+                "<{}>{} {} {}{}",
+                pri,
+                timestamp.format("%b %e %H:%M:%S"),
+                syslog.hostname.as_deref().unwrap_or("-"),
+                tag,
+                message.msg
+            )
+        }
+    }
+}
+
+/**
+ * Render the structured data as SD-ELEMENTs, e.g. `[timeQuality tzKnown="1"]`, or the NILVALUE
+ * when there isn't any
+ */
+fn format_structured_data(sd: &StructuredData) -> String {
+    if sd.is_empty() {
+        return "-".to_string();
+    }
+
+    let mut formatted = String::new();
+    for (id, params) in sd.iter() {
+        formatted.push('[');
+        formatted.push_str(id);
+        for (name, value) in params.iter() {
+            /*
+             * RFC 5424 requires these characters to be escaped in PARAM-VALUEs
+             */
+            let escaped = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace(']', "\\]");
+            formatted.push_str(&format!(" {}=\"{}\"", name, escaped));
+        }
+        formatted.push(']');
+    }
+    formatted
+}
+
+/**
+ * Strip the port from the address in order to verify the server's certificate
+ */
+fn host_of(address: &str) -> &str {
+    let host = match address.rfind(':') {
+        Some(index) => &address[..index],
+        None => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::SyslogMessage;
+    use crate::rules::Variables;
+    use async_std::io::BufReader;
+    use async_std::net::TcpListener;
+
+    fn message(msg: &str) -> OutputMessage {
+        OutputMessage {
+            msg: msg.to_string(),
+            variables: Variables::new(),
+            syslog: SyslogMessage {
+                msg: msg.to_string(),
+                severity: Some("warning".to_string()),
+                facility: Some("local7".to_string()),
+                hostname: Some("coconut".to_string()),
+                appname: Some("tyler".to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_format_rfc5424() {
+        let formatted = format_message(SyslogFormat::Rfc5424, &message("hello"));
+        assert!(formatted.starts_with("<188>1 "));
+        assert!(formatted.ends_with(" coconut tyler - - - hello"));
+    }
+
+    #[test]
+    fn test_format_rfc3164() {
+        let formatted = format_message(SyslogFormat::Rfc3164, &message("hello"));
+        assert!(formatted.starts_with("<188>"));
+        assert!(formatted.ends_with(" coconut tyler: hello"));
+    }
+
+    #[test]
+    fn test_format_defaults() {
+        let mut msg = message("hello");
+        msg.syslog.severity = None;
+        msg.syslog.facility = None;
+        msg.syslog.hostname = None;
+        let formatted = format_message(SyslogFormat::Rfc5424, &msg);
+        assert!(formatted.starts_with("<13>1 "));
+        assert!(formatted.ends_with(" - tyler - - - hello"));
+    }
+
+    #[test]
+    fn test_format_preserves_header_fields() {
+        let mut msg = message("hello");
+        msg.syslog.procid = Some("4321".to_string());
+        msg.syslog.msgid = Some("ID47".to_string());
+        msg.syslog.timestamp = Some(
+            DateTime::parse_from_rfc3339("2020-04-18T15:16:09.956153-07:00")
+                .expect("Failed to parse the timestamp"),
+        );
+        let mut params = std::collections::BTreeMap::new();
+        params.insert("ip".to_string(), r#"10.0.0.1 "quoted" ]"#.to_string());
+        msg.syslog
+            .structured_data
+            .insert("origin".to_string(), params);
+
+        assert_eq!(
+            r#"<188>1 2020-04-18T15:16:09.956153-07:00 coconut tyler 4321 ID47 [origin ip="10.0.0.1 \"quoted\" \]"] hello"#,
+            format_message(SyslogFormat::Rfc5424, &msg)
+        );
+        assert!(
+            format_message(SyslogFormat::Rfc3164, &msg).ends_with(" coconut tyler[4321]: hello")
+        );
+    }
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!("siem.example.com", host_of("siem.example.com:6514"));
+        assert_eq!("::1", host_of("[::1]:6514"));
+    }
+
+    fn output(address: String, protocol: Protocol, format: SyslogFormat) -> SyslogOutput {
+        let (stats, _) = channel(10);
+        SyslogOutput::new(
+            "siem",
+            address,
+            protocol,
+            false,
+            format,
+            Framing::Auto,
+            stats,
+        )
+    }
+
+    /**
+     * Find a local address which nobody is listening on
+     */
+    async fn unused_address() -> String {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind")
+            .local_addr()
+            .expect("No local address")
+            .to_string()
+    }
+
+    async fn read_line(listener: &TcpListener) -> String {
+        let (stream, _) = listener.accept().await.expect("Failed to accept");
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .await
+            .expect("Failed to read");
+        line
+    }
+
+    #[test]
+    fn test_relay_over_tcp() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind");
+            let address = listener.local_addr().expect("No local address").to_string();
+            let output = output(address, Protocol::Tcp, SyslogFormat::Rfc3164);
+
+            output
+                .send(&message("hi"))
+                .await
+                .expect("Failed to relay the message");
+
+            assert!(read_line(&listener).await.ends_with(" coconut tyler: hi\n"));
+        });
+    }
+
+    #[test]
+    fn test_relay_over_udp() {
+        task::block_on(async {
+            let socket = UdpSocket::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind");
+            let address = socket.local_addr().expect("No local address").to_string();
+            let output = output(address, Protocol::Udp, SyslogFormat::Rfc5424);
+
+            output
+                .send(&message("hi"))
+                .await
+                .expect("Failed to relay the message");
+
+            let mut buffer = vec![0; 1024];
+            let (len, _) = socket.recv_from(&mut buffer).await.expect("Failed to recv");
+            let received = String::from_utf8_lossy(&buffer[..len]);
+            assert!(received.ends_with(" coconut tyler - - - hi"));
+        });
+    }
+
+    /**
+     * The message should only be counted as sent once it has been written to the server
+     */
+    #[test]
+    fn test_relay_records_sent() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("Failed to bind");
+            let address = listener.local_addr().expect("No local address").to_string();
+            let (stats, stats_rx) = channel(10);
+            let output = SyslogOutput::new(
+                "siem",
+                address,
+                Protocol::Tcp,
+                false,
+                SyslogFormat::Rfc3164,
+                Framing::Auto,
+                stats,
+            );
+            assert!(output.records_delivery());
+
+            output
+                .send(&message("hi"))
+                .await
+                .expect("Failed to queue the message");
+            read_line(&listener).await;
+
+            let (stat, count) = stats_rx.recv().await.expect("No statistic was recorded");
+            assert_eq!(
+                Stats::OutputSent {
+                    name: "siem".to_string()
+                },
+                stat
+            );
+            assert_eq!(1, count);
+        });
+    }
+
+    /**
+     * Messages sent while the server is unreachable should wait to be relayed once it is back
+     */
+    #[test]
+    fn test_relay_after_reconnecting() {
+        task::block_on(async {
+            let address = unused_address().await;
+            let output = output(address.clone(), Protocol::Tcp, SyslogFormat::Rfc3164);
+
+            output
+                .send(&message("hi"))
+                .await
+                .expect("Failed to queue the message");
+            task::sleep(Duration::from_millis(100)).await;
+
+            let listener = TcpListener::bind(&address).await.expect("Failed to bind");
+            let line = io::timeout(INITIAL_BACKOFF * 2, async {
+                Ok(read_line(&listener).await)
+            })
+            .await
+            .expect("The message was never relayed");
+            assert!(line.ends_with(" coconut tyler: hi\n"));
+        });
+    }
+
+    /**
+     * Sending should never wait on an unreachable server, instead failing once the queue is full
+     */
+    #[test]
+    fn test_send_when_queue_is_full() {
+        task::block_on(async {
+            let output = output(unused_address().await, Protocol::Tcp, SyslogFormat::Rfc5424);
+
+            let mut failed = 0;
+            for _ in 0..QUEUED_MESSAGES + 2 {
+                if output.send(&message("hi")).await.is_err() {
+                    failed += 1;
+                }
+            }
+            assert!(failed >= 1);
+        });
+    }
+}
@@ -1,4 +1,22 @@
+use chrono::prelude::*;
 use log::*;
+use std::collections::BTreeMap;
+
+/**
+ * Structured data is kept as a map of SD-IDs to their parameters, e.g.
+ * `[timeQuality tzKnown="1"]` becomes `{"timeQuality": {"tzKnown": "1"}}`
+ */
+pub type StructuredData = BTreeMap<String, BTreeMap<String, String>>;
+
+/**
+ * Look up a structured data parameter by its `id.param` path, e.g. `timeQuality.tzKnown`
+ *
+ * SD-IDs may contain periods, such as `origin@32473.1`, so the path is split on the last one
+ */
+pub fn structured_data_param<'a>(sd: &'a StructuredData, path: &str) -> Option<&'a String> {
+    let index = path.rfind('.')?;
+    sd.get(&path[..index])?.get(&path[index + 1..])
+}
 
 /**
  * Enum of syslog parse related errors
@@ -12,13 +30,89 @@ pub enum SyslogErrors {
  * SyslogMessage is just a wrapper struct to allow us to deserialize RFC 5424 and RFC 3164 syslog
  * messages into some format that can be passed throughout hotdog
  */
-#[derive(Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct SyslogMessage {
     pub msg: String,
     pub severity: Option<String>,
     pub facility: Option<String>,
     pub hostname: Option<String>,
     pub appname: Option<String>,
+    pub procid: Option<String>,
+    pub msgid: Option<String>,
+    /**
+     * The timestamp from the syslog header, rather than when hotdog received the message
+     */
+    pub timestamp: Option<DateTime<FixedOffset>>,
+    pub structured_data: StructuredData,
+}
+
+/**
+ * Return the numeric code for the named severity, e.g. `warning` is 4
+ */
+pub fn severity_code(name: &str) -> Option<u8> {
+    match name {
+        "emerg" | "emergency" | "panic" => Some(0),
+        "alert" => Some(1),
+        "crit" | "critical" => Some(2),
+        "err" | "error" => Some(3),
+        "warning" | "warn" => Some(4),
+        "notice" => Some(5),
+        "info" | "informational" => Some(6),
+        "debug" => Some(7),
+        _ => None,
+    }
+}
+
+/**
+ * Return the numeric code for the named facility, e.g. `local7` is 23
+ */
+pub fn facility_code(name: &str) -> Option<u8> {
+    match name {
+        "kern" => Some(0),
+        "user" => Some(1),
+        "mail" => Some(2),
+        "daemon" => Some(3),
+        "auth" => Some(4),
+        "syslog" => Some(5),
+        "lpr" => Some(6),
+        "news" => Some(7),
+        "uucp" => Some(8),
+        "cron" => Some(9),
+        "authpriv" => Some(10),
+        "ftp" => Some(11),
+        "ntp" => Some(12),
+        "audit" => Some(13),
+        "alert" => Some(14),
+        "clockd" => Some(15),
+        "local0" => Some(16),
+        "local1" => Some(17),
+        "local2" => Some(18),
+        "local3" => Some(19),
+        "local4" => Some(20),
+        "local5" => Some(21),
+        "local6" => Some(22),
+        "local7" => Some(23),
+        _ => None,
+    }
+}
+
+/**
+ * syslog_rfc5424 converts the timestamp to UTC, so the offset it was sent with is taken from the
+ * TIMESTAMP field of the header, which follows the PRI and VERSION
+ */
+fn offset_of(line: &str) -> i32 {
+    line.split(' ')
+        .nth(1)
+        .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+        .map_or(0, |timestamp| timestamp.offset().local_minus_utc())
+}
+
+/**
+ * Convert the seconds since the epoch to a timestamp with the offset, which is None rather than a
+ * panic for values out of range
+ */
+fn timestamp_for(secs: i64, nanos: u32, offset: i32) -> Option<DateTime<FixedOffset>> {
+    FixedOffset::east_opt(offset).and_then(|o| o.timestamp_opt(secs, nanos).single())
 }
 
 /**
@@ -27,12 +121,28 @@ pub struct SyslogMessage {
 pub fn parse_line(line: String) -> std::result::Result<SyslogMessage, SyslogErrors> {
     match syslog_rfc5424::parse_message(&line) {
         Ok(msg) => {
+            let mut structured_data = StructuredData::new();
+            for (id, params) in msg.sd.iter() {
+                structured_data.insert(id.to_string(), params.clone());
+            }
+            let nanos = msg.timestamp_nanos.unwrap_or(0) as u32;
+            let offset = offset_of(&line);
+
             let wrapped = SyslogMessage {
                 msg: msg.msg,
                 severity: Some(msg.severity.as_str().to_string()),
                 facility: Some(msg.facility.as_str().to_string()),
                 hostname: msg.hostname,
                 appname: msg.appname,
+                procid: msg.procid.map(|procid| match procid {
+                    syslog_rfc5424::message::ProcId::PID(pid) => pid.to_string(),
+                    syslog_rfc5424::message::ProcId::Name(name) => name,
+                }),
+                msgid: msg.msgid,
+                timestamp: msg
+                    .timestamp
+                    .and_then(|secs| timestamp_for(secs, nanos, offset)),
+                structured_data,
             };
             Ok(wrapped)
         }
@@ -56,6 +166,24 @@ pub fn parse_line(line: String) -> std::result::Result<SyslogMessage, SyslogErro
                         .hostname
                         .map_or_else(|| None, |h| Some(h.to_string())),
                     appname: parsed.appname.map_or_else(|| None, |a| Some(a.to_string())),
+                    procid: parsed.procid.map(|procid| match procid {
+                        syslog_loose::ProcId::PID(pid) => pid.to_string(),
+                        syslog_loose::ProcId::Name(name) => name.to_string(),
+                    }),
+                    msgid: parsed.msgid.map(|m| m.to_string()),
+                    timestamp: parsed.timestamp,
+                    structured_data: parsed
+                        .structured_data
+                        .iter()
+                        .map(|element| {
+                            let params = element
+                                .params
+                                .iter()
+                                .map(|(name, value)| (name.to_string(), value.to_string()))
+                                .collect();
+                            (element.id.to_string(), params)
+                        })
+                        .collect(),
                 };
                 return Ok(wrapped);
             }
@@ -94,6 +222,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_5424_header_fields() {
+        let buffer = r#"<13>1 2020-04-18T15:16:09.956153-07:00 coconut tyler 4321 ID47 [timeQuality tzKnown="1" isSynced="1"][origin ip="10.0.0.1"] hi"#.to_string();
+        let msg = parse_line(buffer).expect("Failed to parse");
+
+        assert_eq!(Some("4321".to_string()), msg.procid);
+        assert_eq!(Some("ID47".to_string()), msg.msgid);
+        assert_eq!(
+            Some(
+                DateTime::parse_from_rfc3339("2020-04-18T15:16:09.956153-07:00")
+                    .expect("Failed to parse the expected timestamp")
+            ),
+            msg.timestamp
+        );
+        assert_eq!(
+            Some(-7 * 3600),
+            msg.timestamp.map(|t| t.offset().local_minus_utc())
+        );
+        assert_eq!(
+            Some(&"1".to_string()),
+            msg.structured_data["timeQuality"].get("tzKnown")
+        );
+        assert_eq!(
+            Some(&"10.0.0.1".to_string()),
+            msg.structured_data["origin"].get("ip")
+        );
+    }
+
+    #[test]
+    fn test_timestamp_out_of_range() {
+        assert!(timestamp_for(0, 0, 0).is_some());
+        assert_eq!(None, timestamp_for(i64::MAX, 0, 0));
+        assert_eq!(None, timestamp_for(0, 0, 86_400));
+    }
+
+    #[test]
+    fn test_structured_data_param() {
+        let mut sd = StructuredData::new();
+        let mut params = BTreeMap::new();
+        params.insert("ip".to_string(), "10.0.0.1".to_string());
+        sd.insert("origin@32473.1".to_string(), params);
+
+        assert_eq!(
+            Some(&"10.0.0.1".to_string()),
+            structured_data_param(&sd, "origin@32473.1.ip")
+        );
+        assert_eq!(None, structured_data_param(&sd, "origin@32473.1.software"));
+        assert_eq!(None, structured_data_param(&sd, "origin"));
+    }
+
     #[test]
     fn test_3164() {
         let buffer = r#"<190>May 13 21:45:18 coconut hotdog: hi"#.to_string();
@@ -109,4 +287,18 @@ mod tests {
             assert!(false);
         }
     }
+
+    #[test]
+    fn test_severity_code() {
+        assert_eq!(Some(4), severity_code("warning"));
+        assert_eq!(Some(6), severity_code("info"));
+        assert_eq!(None, severity_code("loud"));
+    }
+
+    #[test]
+    fn test_facility_code() {
+        assert_eq!(Some(1), facility_code("user"));
+        assert_eq!(Some(23), facility_code("local7"));
+        assert_eq!(None, facility_code("local8"));
+    }
 }
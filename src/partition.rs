@@ -0,0 +1,114 @@
+use crate::settings::Partitioner;
+/**
+ * The partition module contains the hashing strategies which can be used to select the partition
+ * of a Kafka message from its key.
+ *
+ * Both strategies are implemented to produce the same results as their librdkafka and Java client
+ * counterparts, so that hotdog will place keys on the same partitions as other producers
+ */
+
+/**
+ * Select the partition for the given key out of the topic's partition count
+ */
+pub fn partition_for(partitioner: Partitioner, key: &[u8], partition_count: i32) -> i32 {
+    let hash = match partitioner {
+        Partitioner::Murmur2 => (murmur2(key) & 0x7fff_ffff) as i64,
+        Partitioner::ConsistentRandom => crc32(key) as i64,
+    };
+    (hash % partition_count as i64) as i32
+}
+
+/**
+ * The murmur2 hash as implemented by the Java client's default partitioner
+ */
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let mut h: u32 = 0x9747_b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    let tail = chunks.remainder();
+    if tail.len() >= 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+/**
+ * The IEEE CRC32 checksum, which librdkafka uses for its consistent partitioners
+ */
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xffff_ffff;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Expected values are taken from the librdkafka murmur2 unit tests
+     */
+    #[test]
+    fn test_murmur2() {
+        assert_eq!(0xd067_cf64, murmur2(b"kafka"));
+        assert_eq!(0x8f55_2b0c, murmur2(b"giberish123456789"));
+        assert_eq!(0x9fc9_7b14, murmur2(b"1234"));
+        assert_eq!(0xe7c0_09ca, murmur2(b"234"));
+        assert_eq!(0x8739_30da, murmur2(b"34"));
+        assert_eq!(0x5a4b_5ca1, murmur2(b"4"));
+        assert_eq!(0x106e_08d9, murmur2(b""));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_partition_for_murmur2() {
+        assert_eq!(80, partition_for(Partitioner::Murmur2, b"kafka", 100));
+        assert_eq!(
+            20,
+            partition_for(Partitioner::Murmur2, b"giberish123456789", 100)
+        );
+    }
+
+    #[test]
+    fn test_partition_for_consistent_random() {
+        assert_eq!(
+            (606_994_685 % 12) as i32,
+            partition_for(Partitioner::ConsistentRandom, b"coconut", 12)
+        );
+    }
+}
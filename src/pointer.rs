@@ -0,0 +1,165 @@
+/**
+ * The pointer module contains the functions for modifying JSON values at JSON pointers
+ * (RFC 6901), such as `/meta/user/0`, which serde_json can only look up
+ */
+use serde_json::{Map, Value};
+
+/**
+ * Split the pointer into its unescaped reference tokens
+ */
+fn tokens(pointer: &str) -> Vec<String> {
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+/**
+ * Split the pointer into the pointer of its parent and its last reference token
+ */
+fn split_last(pointer: &str) -> Option<(&str, String)> {
+    let index = pointer.rfind('/')?;
+    let last = pointer[index + 1..].replace("~1", "/").replace("~0", "~");
+    Some((&pointer[..index], last))
+}
+
+/**
+ * Returns true if the pointer is valid and refers to something other than the whole document
+ */
+pub fn is_valid(pointer: &str) -> bool {
+    pointer.starts_with('/')
+}
+
+/**
+ * Remove and return the value at the pointer, if it exists
+ */
+pub fn take(json: &mut Value, pointer: &str) -> Option<Value> {
+    let (parent, last) = split_last(pointer)?;
+
+    match json.pointer_mut(parent)? {
+        Value::Object(map) => map.remove(&last),
+        Value::Array(array) => {
+            let index = last.parse::<usize>().ok()?;
+            if index < array.len() {
+                Some(array.remove(index))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Find the value at the pointer, creating any missing objects along the way, so that it can be
+ * modified in place.
+ *
+ * Returns None if the pointer refers to the whole document, or can't be followed, such as when it
+ * passes through a string or indexes past the end of an array
+ */
+pub fn entry<'a>(json: &'a mut Value, pointer: &str) -> Option<&'a mut Value> {
+    let tokens = tokens(pointer);
+    if tokens.is_empty() {
+        return None;
+    }
+    let mut current = json;
+
+    for token in tokens.iter() {
+        if current.is_null() {
+            *current = Value::Object(Map::new());
+        }
+
+        current = match current {
+            Value::Object(map) => map.entry(token.to_string()).or_insert(Value::Null),
+            Value::Array(array) => {
+                let position = match token.parse::<usize>() {
+                    Ok(position) if position <= array.len() => position,
+                    /*
+                     * `-` refers to the element after the end of the array
+                     */
+                    _ if token == "-" => array.len(),
+                    _ => return None,
+                };
+
+                if position == array.len() {
+                    array.push(Value::Null);
+                }
+                &mut array[position]
+            }
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+/**
+ * Set the value at the pointer, creating any missing objects along the way.
+ *
+ * Returns false if the value couldn't be set, such as when the pointer passes through a string
+ */
+pub fn set(json: &mut Value, pointer: &str, value: Value) -> bool {
+    match entry(json, pointer) {
+        Some(target) => {
+            *target = value;
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_take() {
+        let mut value = json!({"meta": {"user": "tyler", "a/b": 1}, "tags": ["a", "b"]});
+
+        assert_eq!(Some(json!("tyler")), take(&mut value, "/meta/user"));
+        assert_eq!(Some(json!(1)), take(&mut value, "/meta/a~1b"));
+        assert_eq!(Some(json!("a")), take(&mut value, "/tags/0"));
+        assert_eq!(None, take(&mut value, "/missing/user"));
+        assert_eq!(json!({"meta": {}, "tags": ["b"]}), value);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut value = json!({"tags": ["a"]});
+
+        assert!(set(&mut value, "/meta/user", json!("tyler")));
+        assert!(set(&mut value, "/tags/-", json!("b")));
+        assert!(set(&mut value, "/tags/0", json!("c")));
+        assert_eq!(
+            json!({"meta": {"user": "tyler"}, "tags": ["c", "b"]}),
+            value
+        );
+    }
+
+    #[test]
+    fn test_set_through_a_string() {
+        let mut value = json!({"meta": "tyler"});
+        assert!(!set(&mut value, "/meta/user", json!("tyler")));
+        assert!(!set(&mut value, "", json!("tyler")));
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut value = json!({"meta": [{"user": "tyler"}], "a/b": {}});
+
+        assert_eq!(Some(&mut json!("tyler")), entry(&mut value, "/meta/0/user"));
+        assert_eq!(Some(&mut json!({})), entry(&mut value, "/a~1b"));
+        assert_eq!(Some(&mut Value::Null), entry(&mut value, "/new/user"));
+        assert_eq!(None, entry(&mut value, "/meta/user"));
+        assert_eq!(None, entry(&mut value, "/meta/5"));
+        assert_eq!(None, entry(&mut value, ""));
+    }
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("/meta"));
+        assert!(!is_valid("meta"));
+        assert!(!is_valid(""));
+    }
+}
@@ -0,0 +1,191 @@
+/**
+ * The rate_limit module contains the token buckets which enforce the rate limit actions of rules.
+ *
+ * Each bucket holds up to `burst` tokens and is refilled at `per_second` tokens every second, with
+ * every message allowed through taking one token
+ */
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/**
+ * The number of keyed buckets after which the buckets which have refilled are discarded, since
+ * they are no different to a newly created bucket. If none have refilled, the least recently
+ * used bucket is discarded instead
+ */
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /**
+     * Create the limiter, whose burst defaults to a second's worth of messages
+     */
+    pub fn new(per_second: f64, burst: Option<f64>) -> Result<Self, String> {
+        if per_second.is_nan() || per_second <= 0.0 {
+            return Err(format!("{} is not a positive rate per second", per_second));
+        }
+
+        let burst = burst.unwrap_or_else(|| per_second.max(1.0));
+        if burst.is_nan() || burst < 1.0 {
+            return Err(format!(
+                "The burst {} must allow at least one message",
+                burst
+            ));
+        }
+
+        Ok(RateLimiter {
+            per_second,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /**
+     * Returns true if a message with the given key, which is empty when the limit is not keyed,
+     * is within the limit
+     */
+    pub fn allow(&self, key: &str) -> bool {
+        self.allow_at(key, Instant::now())
+    }
+
+    fn allow_at(&self, key: &str, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock();
+
+        if !buckets.contains_key(key) && buckets.len() >= MAX_BUCKETS {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.retain(|_, bucket| refilled(bucket, now, per_second) < burst);
+
+            if buckets.len() >= MAX_BUCKETS {
+                let oldest = buckets
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.updated)
+                    .map(|(key, _)| key.to_string());
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        bucket.tokens = refilled(bucket, now, self.per_second).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/**
+ * The tokens the bucket would have at `now`, without being capped to the burst
+ */
+fn refilled(bucket: &Bucket, now: Instant, per_second: f64) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens + elapsed * per_second
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_new_invalid() {
+        assert!(RateLimiter::new(0.0, None).is_err());
+        assert!(RateLimiter::new(-1.0, None).is_err());
+        assert!(RateLimiter::new(10.0, Some(0.5)).is_err());
+    }
+
+    #[test]
+    fn test_default_burst() {
+        let limiter = RateLimiter::new(0.5, None).expect("Failed to create the limiter");
+        assert!((limiter.burst - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimiter::new(2.0, Some(3.0)).expect("Failed to create the limiter");
+        let now = Instant::now();
+
+        assert!(limiter.allow_at("", now));
+        assert!(limiter.allow_at("", now));
+        assert!(limiter.allow_at("", now));
+        assert!(!limiter.allow_at("", now));
+
+        /*
+         * Half a second at two per second is enough for one more message
+         */
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow_at("", later));
+        assert!(!limiter.allow_at("", later));
+
+        /*
+         * The bucket never holds more than the burst
+         */
+        let much_later = now + Duration::from_secs(60);
+        assert!((0..3).all(|_| limiter.allow_at("", much_later)));
+        assert!(!limiter.allow_at("", much_later));
+    }
+
+    #[test]
+    fn test_keyed_buckets() {
+        let limiter = RateLimiter::new(1.0, Some(1.0)).expect("Failed to create the limiter");
+        let now = Instant::now();
+
+        assert!(limiter.allow_at("coconut", now));
+        assert!(limiter.allow_at("banana", now));
+        assert!(!limiter.allow_at("coconut", now));
+    }
+
+    #[test]
+    fn test_refilled_buckets_are_discarded() {
+        let limiter = RateLimiter::new(1.0, Some(1.0)).expect("Failed to create the limiter");
+        let now = Instant::now();
+
+        for i in 0..MAX_BUCKETS {
+            limiter.allow_at(&i.to_string(), now);
+        }
+        assert!(limiter.allow_at("new", now + Duration::from_secs(1)));
+        assert_eq!(1, limiter.buckets.lock().len());
+    }
+
+    #[test]
+    fn test_oldest_bucket_is_evicted() {
+        let limiter = RateLimiter::new(1.0, Some(1.0)).expect("Failed to create the limiter");
+        let now = Instant::now();
+
+        /*
+         * Drain every bucket, with the first being the least recently used
+         */
+        for i in 0..MAX_BUCKETS {
+            let at = now + Duration::from_micros(i as u64);
+            assert!(limiter.allow_at(&i.to_string(), at));
+        }
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.allow_at("new", later));
+
+        let buckets = limiter.buckets.lock();
+        assert_eq!(MAX_BUCKETS, buckets.len());
+        assert!(!buckets.contains_key("0"));
+        assert!(buckets.contains_key("1"));
+    }
+}
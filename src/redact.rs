@@ -0,0 +1,327 @@
+use crate::settings::Detector;
+/**
+ * The redact module contains the detectors which find personal information, such as email
+ * addresses, in messages and the Redactor which masks or hashes what they find
+ */
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/**
+ * The number of hexadecimal characters of the keyed hash which replace a match
+ */
+const HASH_LENGTH: usize = 16;
+
+/**
+ * The number of matches redacted by each detector, keyed by the detector's name
+ */
+pub type Redactions = HashMap<String, usize>;
+
+/**
+ * Checks that a match is genuine rather than just resembling the secret
+ */
+type Validator = fn(&str) -> bool;
+
+/**
+ * A single named regular expression, along with an optional check that a match is genuine, such
+ * as the Luhn checksum of a credit card number
+ */
+struct Detection {
+    name: String,
+    regex: Regex,
+    validate: Option<Validator>,
+}
+
+enum Replacement {
+    Mask(String),
+    Hash(Vec<u8>),
+}
+
+pub struct Redactor {
+    detections: Vec<Detection>,
+    replacement: Replacement,
+}
+
+impl Redactor {
+    /**
+     * Compile the detectors and patterns, using every built-in detector if neither are given.
+     *
+     * The patterns are applied after the built-in detectors, in the order of their names
+     */
+    pub fn new(
+        detectors: &[Detector],
+        patterns: &HashMap<String, String>,
+        mask: &str,
+        hash_key: Option<&str>,
+    ) -> Result<Self, regex::Error> {
+        let detectors = if detectors.is_empty() && patterns.is_empty() {
+            vec![
+                Detector::Email,
+                Detector::BearerToken,
+                Detector::CreditCard,
+                Detector::Ipv4,
+                Detector::Ipv6,
+            ]
+        } else {
+            detectors.to_vec()
+        };
+
+        let mut detections = detectors
+            .into_iter()
+            .map(builtin)
+            .collect::<Result<Vec<Detection>, regex::Error>>()?;
+
+        let mut names: Vec<&String> = patterns.keys().collect();
+        names.sort();
+        for name in names {
+            detections.push(Detection {
+                name: name.to_string(),
+                regex: Regex::new(&patterns[name])?,
+                validate: None,
+            });
+        }
+
+        let replacement = match hash_key {
+            Some(key) => Replacement::Hash(key.as_bytes().to_vec()),
+            None => Replacement::Mask(mask.to_string()),
+        };
+
+        Ok(Redactor {
+            detections,
+            replacement,
+        })
+    }
+
+    /**
+     * Redact the text, returning it along with the number of matches of each detector
+     */
+    pub fn redact(&self, text: &str) -> (String, Redactions) {
+        let mut redactions = Redactions::new();
+        let redacted = self.redact_into(text, &mut redactions);
+        (redacted, redactions)
+    }
+
+    /**
+     * Redact every string within the JSON value, counting the matches into `redactions`
+     */
+    pub fn redact_value(&self, json: &mut Value, redactions: &mut Redactions) {
+        match json {
+            Value::String(s) => *s = self.redact_into(s, redactions),
+            Value::Array(array) => {
+                for value in array.iter_mut() {
+                    self.redact_value(value, redactions);
+                }
+            }
+            Value::Object(map) => {
+                for value in map.values_mut() {
+                    self.redact_value(value, redactions);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn redact_into(&self, text: &str, redactions: &mut Redactions) -> String {
+        let mut redacted = text.to_string();
+
+        for detection in self.detections.iter() {
+            let mut result = String::with_capacity(redacted.len());
+            let mut last = 0;
+            let mut count = 0;
+
+            for captures in detection.regex.captures_iter(&redacted) {
+                let whole = captures.get(0).expect("The whole match is always present");
+                let secret = captures.name("secret").unwrap_or(whole);
+
+                if let Some(validate) = detection.validate {
+                    if !validate(secret.as_str()) {
+                        continue;
+                    }
+                }
+
+                result.push_str(&redacted[last..secret.start()]);
+                result.push_str(&self.replacement_for(secret.as_str()));
+                last = secret.end();
+                count += 1;
+            }
+
+            if count > 0 {
+                result.push_str(&redacted[last..]);
+                redacted = result;
+                *redactions.entry(detection.name.clone()).or_insert(0) += count;
+            }
+        }
+        redacted
+    }
+
+    fn replacement_for(&self, secret: &str) -> String {
+        match &self.replacement {
+            Replacement::Mask(mask) => mask.to_string(),
+            Replacement::Hash(key) => {
+                let mut mac =
+                    Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+                mac.input(secret.as_bytes());
+                let hex: String = mac
+                    .result()
+                    .code()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                hex[..HASH_LENGTH].to_string()
+            }
+        }
+    }
+}
+
+/**
+ * Compile the regular expression for the built-in detector
+ */
+fn builtin(detector: Detector) -> Result<Detection, regex::Error> {
+    let (name, pattern, validate): (&str, &str, Option<Validator>) = match detector {
+        Detector::Email => (
+            "email",
+            r"(?i)\b[A-Z0-9._%+-]+@[A-Z0-9.-]+\.[A-Z]{2,}\b",
+            None,
+        ),
+        Detector::Ipv4 => (
+            "ipv4",
+            r"\b(?:(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.){3}(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\b",
+            None,
+        ),
+        /*
+         * Addresses must either be complete or compressed with `::`, so that times such as
+         * `15:16:09` are not mistaken for them
+         */
+        Detector::Ipv6 => (
+            "ipv6",
+            r"(?i)\b(?:[0-9a-f]{1,4}:){7}[0-9a-f]{1,4}\b|\b(?:[0-9a-f]{1,4}:){1,6}(?::[0-9a-f]{1,4}){1,6}\b|::(?:[0-9a-f]{1,4}:){0,5}[0-9a-f]{1,4}\b",
+            None,
+        ),
+        Detector::CreditCard => ("creditCard", r"\b(?:\d[ -]?){12,18}\d\b", Some(luhn)),
+        Detector::BearerToken => (
+            "bearerToken",
+            r"(?i)\bbearer\s+(?P<secret>[a-z0-9\-._~+/]+=*)",
+            None,
+        ),
+    };
+
+    Ok(Detection {
+        name: name.to_string(),
+        regex: Regex::new(pattern)?,
+        validate,
+    })
+}
+
+/**
+ * Check the Luhn checksum of the digits, which every credit card number passes
+ */
+fn luhn(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(index, digit)| {
+            if index % 2 == 1 {
+                let doubled = digit * 2;
+                if doubled > 9 {
+                    doubled - 9
+                } else {
+                    doubled
+                }
+            } else {
+                *digit
+            }
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn redactor() -> Redactor {
+        Redactor::new(&[], &HashMap::new(), "[REDACTED]", None).expect("Failed to compile")
+    }
+
+    #[test]
+    fn test_redact_builtins() {
+        let (redacted, redactions) = redactor().redact(
+            "tyler@example.com from 10.0.0.1 and fe80::1ff:fe23:4567:890a paid with 4111 1111 1111 1111 using Bearer abc.def-123 at 15:16:09",
+        );
+
+        assert_eq!(
+            "[REDACTED] from [REDACTED] and [REDACTED] paid with [REDACTED] using Bearer [REDACTED] at 15:16:09",
+            redacted
+        );
+        assert_eq!(Some(&1), redactions.get("email"));
+        assert_eq!(Some(&1), redactions.get("ipv4"));
+        assert_eq!(Some(&1), redactions.get("ipv6"));
+        assert_eq!(Some(&1), redactions.get("creditCard"));
+        assert_eq!(Some(&1), redactions.get("bearerToken"));
+    }
+
+    #[test]
+    fn test_redact_invalid_card_number() {
+        let (redacted, redactions) = redactor().redact("order 4111 1111 1111 1112");
+        assert_eq!("order 4111 1111 1111 1112", redacted);
+        assert!(redactions.is_empty());
+    }
+
+    #[test]
+    fn test_redact_patterns() {
+        let mut patterns = HashMap::new();
+        patterns.insert(
+            "ssn".to_string(),
+            r"ssn=(?P<secret>\d{3}-\d{2}-\d{4})".to_string(),
+        );
+        let redactor =
+            Redactor::new(&[Detector::Email], &patterns, "***", None).expect("Failed to compile");
+
+        let (redacted, redactions) = redactor.redact("ssn=123-45-6789 from 10.0.0.1");
+        assert_eq!("ssn=*** from 10.0.0.1", redacted);
+        assert_eq!(Some(&1), redactions.get("ssn"));
+    }
+
+    #[test]
+    fn test_redact_hash() {
+        let redactor = Redactor::new(&[Detector::Email], &HashMap::new(), "", Some("sekrit"))
+            .expect("Failed to compile");
+
+        let (first, _) = redactor.redact("tyler@example.com");
+        let (second, _) = redactor.redact("user tyler@example.com");
+        assert_eq!(HASH_LENGTH, first.len());
+        assert!(first.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(format!("user {}", first), second);
+    }
+
+    #[test]
+    fn test_redact_value() {
+        let mut value = json!({"user": {"email": "tyler@example.com", "ids": ["10.0.0.1", 1]}});
+        let mut redactions = Redactions::new();
+
+        redactor().redact_value(&mut value, &mut redactions);
+        assert_eq!(
+            json!({"user": {"email": "[REDACTED]", "ids": ["[REDACTED]", 1]}}),
+            value
+        );
+        assert_eq!(2, redactions.values().sum::<usize>());
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let mut patterns = HashMap::new();
+        patterns.insert("broken".to_string(), "(".to_string());
+        assert!(Redactor::new(&[], &patterns, "", None).is_err());
+    }
+
+    #[test]
+    fn test_luhn() {
+        assert!(luhn("4111-1111-1111-1111"));
+        assert!(!luhn("4111-1111-1111-1112"));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::errors;
+use crate::rules::{self, RuleEngine};
+use crate::settings::{self, Settings};
+/**
+ * The reload module is responsible for swapping in a freshly loaded configuration while hotdog
+ * is running, without dropping any of the existing connections
+ */
+use async_std::sync::Arc;
+use log::*;
+use parking_lot::RwLock;
+use signal_hook::iterator::Signals;
+use std::collections::HashSet;
+
+/**
+ * ReloadableSettings holds the RuleEngine compiled from the currently active Settings, which
+ * connections should consult for every message they process
+ */
+pub struct ReloadableSettings {
+    /**
+     * The configuration file which will be re-read on reload
+     */
+    file: String,
+    /**
+     * The names of the outputs which were created from the initial settings, since the outputs
+     * cannot be changed without a restart
+     */
+    outputs: HashSet<String>,
+    current: RwLock<Arc<RuleEngine>>,
+}
+
+impl ReloadableSettings {
+    pub fn new(file: &str, settings: Settings) -> Result<Self, errors::HotdogError> {
+        let outputs = settings.global.outputs.keys().cloned().collect();
+
+        Ok(ReloadableSettings {
+            file: file.to_string(),
+            outputs,
+            current: RwLock::new(Arc::new(compile(settings)?)),
+        })
+    }
+
+    /**
+     * Return the RuleEngine for the currently active settings
+     */
+    pub fn current(&self) -> Arc<RuleEngine> {
+        self.current.read().clone()
+    }
+
+    /**
+     * Re-read the configuration file and swap it in, but only if the rules can be compiled.
+     *
+     * If the new configuration is invalid, or its rules refer to outputs which are not running,
+     * the previous settings remain active.
+     *
+     * The state of rate limit, sample, and dedupe actions which haven't changed is kept, see
+     * RuleEngine::inherit
+     *
+     * NOTE: only the rules can be reloaded, changes to listeners, Kafka, outputs, or other global
+     * configuration require a restart.
+     */
+    pub fn reload(&self) -> Result<(), errors::HotdogError> {
+        let settings = settings::try_load(&self.file)?;
+
+        if !self.validate_outputs(&settings) {
+            return Err(errors::HotdogError::InvalidRules);
+        }
+
+        let mut engine = compile(settings)?;
+
+        let mut current = self.current.write();
+        engine.inherit(&current);
+        *current = Arc::new(engine);
+        info!("Reloaded the configuration from {}", self.file);
+        Ok(())
+    }
+
+    /**
+     * Ensure that the rules only send to the outputs which are running, rather than those in the
+     * new configuration which will not be created until hotdog is restarted
+     */
+    fn validate_outputs(&self, settings: &Settings) -> bool {
+        for name in rules::output_names(settings) {
+            if !self.outputs.contains(name) {
+                error!(
+                    "The output `{}` is not running, adding outputs requires a restart",
+                    name
+                );
+                return false;
+            }
+        }
+
+        if settings.global.outputs.len() != self.outputs.len()
+            || settings
+                .global
+                .outputs
+                .keys()
+                .any(|name| !self.outputs.contains(name))
+        {
+            warn!("The outputs have changed, which will not take effect until hotdog is restarted");
+        }
+        true
+    }
+}
+
+/**
+ * Compile the rules for the given settings, which will fail if the rules are invalid
+ */
+fn compile(settings: Settings) -> Result<RuleEngine, errors::HotdogError> {
+    RuleEngine::new(Arc::new(settings)).ok_or(errors::HotdogError::InvalidRules)
+}
+
+/**
+ * Spawn a thread which will reload the settings whenever hotdog receives a SIGHUP
+ */
+pub fn reload_on_sighup(settings: Arc<ReloadableSettings>) -> Result<(), errors::HotdogError> {
+    let signals = Signals::new(&[signal_hook::SIGHUP])?;
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("Received SIGHUP, reloading the configuration");
+
+            if let Err(e) = settings.reload() {
+                error!(
+                    "Failed to reload, keeping the previous configuration: {:?}",
+                    e
+                );
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let file = "test/configs/single-rule-with-merge.yml";
+        let reloadable =
+            ReloadableSettings::new(file, settings::load(file)).expect("Failed to compile");
+        let before = reloadable.current();
+
+        assert!(reloadable.reload().is_ok());
+        assert!(!Arc::ptr_eq(&before, &reloadable.current()));
+    }
+
+    /**
+     * Reloading the same rules should keep the state of the stateful actions
+     */
+    #[test]
+    fn test_reload_keeps_state() {
+        let file = "test/configs/rules-with-rate-limit.yml";
+        let reloadable =
+            ReloadableSettings::new(file, settings::load(file)).expect("Failed to compile");
+        let before = reloadable.current();
+        let limiter =
+            before.limiters[&rules::template_id_for(&before.settings.rules[0], 0)].clone();
+
+        assert!(reloadable.reload().is_ok());
+        let after = reloadable.current();
+        assert!(Arc::ptr_eq(
+            &limiter,
+            &after.limiters[&rules::template_id_for(&after.settings.rules[0], 0)]
+        ));
+    }
+
+    #[test]
+    fn test_reload_invalid_rules() {
+        let reloadable = ReloadableSettings::new(
+            "test/configs/single-rule-with-invalid-jmespath.yml",
+            settings::load("hotdog.yml"),
+        )
+        .expect("Failed to compile");
+        let before = reloadable.current();
+
+        assert!(reloadable.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &reloadable.current()));
+    }
+
+    #[test]
+    fn test_new_invalid_rules() {
+        let file = "test/configs/single-rule-with-invalid-jmespath.yml";
+        assert!(ReloadableSettings::new(file, settings::load(file)).is_err());
+    }
+
+    #[test]
+    fn test_reload_with_running_outputs() {
+        let file = "test/configs/single-rule-with-output.yml";
+        let reloadable =
+            ReloadableSettings::new(file, settings::load(file)).expect("Failed to compile");
+        let before = reloadable.current();
+
+        assert!(reloadable.reload().is_ok());
+        assert!(!Arc::ptr_eq(&before, &reloadable.current()));
+    }
+
+    /**
+     * A reload which adds an output must be rejected, since the output would never be created
+     */
+    #[test]
+    fn test_reload_with_new_outputs() {
+        let reloadable = ReloadableSettings::new(
+            "test/configs/single-rule-with-output.yml",
+            settings::load("hotdog.yml"),
+        )
+        .expect("Failed to compile");
+        let before = reloadable.current();
+
+        assert!(reloadable.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &reloadable.current()));
+    }
+
+    #[test]
+    fn test_reload_missing_file() {
+        let reloadable =
+            ReloadableSettings::new("test/configs/nonexistent.yml", settings::load("hotdog.yml"))
+                .expect("Failed to compile");
+        assert!(reloadable.reload().is_err());
+    }
+}
@@ -1,16 +1,191 @@
+use crate::dedupe::Deduplicator;
 use crate::errors;
+use crate::parse;
+use crate::pointer;
+use crate::rate_limit::RateLimiter;
+use crate::redact::Redactor;
+use crate::sample::Sampler;
 use crate::settings::*;
 /**
  * Rules processing module
  *
  */
 use async_std::{fs::File, io::BufReader, prelude::*, sync::Arc};
+use handlebars::Handlebars;
 use log::*;
 use std::collections::HashMap;
 
+/**
+ * Simple type to capture a map of precompiled jmespath expressions
+ */
+pub type JmesPathExpressions<'a> = HashMap<String, jmespath::Expression<'a>>;
+
+/**
+ * The windows of the dedupe actions, keyed by the template id of the action, which are shared
+ * between every connection
+ */
+pub type Deduplicators = HashMap<String, Arc<Deduplicator>>;
+
+/**
+ * The redactors compiled for the redact actions, keyed by the template id of the action
+ */
+pub type Redactors = HashMap<String, Redactor>;
+
+/**
+ * The token buckets for the rate limit actions, keyed by the template id of the action, which are
+ * shared between every connection
+ */
+pub type RateLimiters = HashMap<String, Arc<RateLimiter>>;
+
+/**
+ * The samplers for the sample actions, keyed by the template id of the action, which are shared
+ * between every connection so that the fraction kept is across all of them
+ */
+pub type Samplers = HashMap<String, Arc<Sampler>>;
+
+/**
+ * The variables available to templates while processing a rule.
+ *
+ * Values are JSON so that nested data, such as structured data, can be referenced with paths like
+ * `{{sd.origin.ip}}`
+ */
+pub type Variables = HashMap<String, serde_json::Value>;
+
+/**
+ * The RuleEngine carries the settings along with the templates and JMESPath expressions compiled
+ * from them.
+ *
+ * It is built once when the settings are loaded (or reloaded) and shared between every
+ * connection, since compiling the rules can be costly for large rule sets.
+ */
+pub struct RuleEngine {
+    pub settings: Arc<Settings>,
+    pub hb: Handlebars<'static>,
+    pub jmespaths: JmesPathExpressions<'static>,
+    pub redactors: Redactors,
+    pub samplers: Samplers,
+    pub limiters: RateLimiters,
+    pub deduplicators: Deduplicators,
+}
+
+impl RuleEngine {
+    /**
+     * Precompile the templates and JMESPath expressions needed for applying the rules
+     *
+     * Failing to precompile is considered fatal since it means the configuration is broken, so
+     * None will be returned
+     */
+    pub fn new(settings: Arc<Settings>) -> Option<Self> {
+        let mut hb = Handlebars::new();
+        /*
+         * The rendered values end up in JSON, Kafka topics and keys, none of which are HTML
+         */
+        hb.register_escape_fn(handlebars::no_escape);
+        let mut jmespaths = JmesPathExpressions::new();
+        let mut redactors = Redactors::new();
+        let mut samplers = Samplers::new();
+        let mut limiters = RateLimiters::new();
+        let mut deduplicators = Deduplicators::new();
+
+        if !precompile_templates(&mut hb, settings.clone()) {
+            error!("Failing to precompile templates is a fatal error, the configuration is broken");
+            return None;
+        }
+
+        if !precompile_jmespath(&mut jmespaths, settings.clone()) {
+            error!("Failing to precompile jmespaths is a fatal error, the configuration is broken");
+            return None;
+        }
+
+        if !precompile_redactors(&mut redactors, &settings) {
+            error!("Failing to precompile redactors is a fatal error, the configuration is broken");
+            return None;
+        }
+
+        if !precompile_samplers(&mut samplers, &settings) {
+            error!("Failing to create samplers is a fatal error, the configuration is broken");
+            return None;
+        }
+
+        if !precompile_rate_limiters(&mut limiters, &settings) {
+            error!("Failing to create rate limiters is a fatal error, the configuration is broken");
+            return None;
+        }
+
+        precompile_deduplicators(&mut deduplicators, &settings);
+
+        if !validate_matchers(&settings) {
+            error!("Rules with invalid matchers is a fatal error, the configuration is broken");
+            return None;
+        }
+
+        if !validate_pointers(&settings) {
+            error!(
+                "Actions with invalid JSON pointers is a fatal error, the configuration is broken"
+            );
+            return None;
+        }
+
+        if !validate_outputs(&settings) {
+            error!("Rules referring to undefined outputs is a fatal error, the configuration is broken");
+            return None;
+        }
+
+        Some(RuleEngine {
+            settings,
+            hb,
+            jmespaths,
+            redactors,
+            samplers,
+            limiters,
+            deduplicators,
+        })
+    }
+
+    /**
+     * Keep the rate limiters, samplers, and dedupe windows of the previous engine for the actions
+     * which are configured identically at the same position in the rules, so that reloading
+     * doesn't reset them. The state of every other action starts afresh
+     */
+    pub fn inherit(&mut self, previous: &RuleEngine) {
+        for (rule_index, rule) in self.settings.rules.iter().enumerate() {
+            let previous_rule = match previous.settings.rules.get(rule_index) {
+                Some(previous_rule) => previous_rule,
+                None => continue,
+            };
+
+            for (index, action) in rule.actions.iter().enumerate() {
+                if previous_rule.actions.get(index) != Some(action) {
+                    continue;
+                }
+                let from = template_id_for(previous_rule, index);
+                let to = template_id_for(rule, index);
+
+                inherit_state(&mut self.limiters, &previous.limiters, &from, &to);
+                inherit_state(&mut self.samplers, &previous.samplers, &from, &to);
+                inherit_state(&mut self.deduplicators, &previous.deduplicators, &from, &to);
+            }
+        }
+    }
+}
+
+/**
+ * Replace the state at `to` with the previous state at `from`, if both exist
+ */
+fn inherit_state<T>(
+    map: &mut HashMap<String, Arc<T>>,
+    previous: &HashMap<String, Arc<T>>,
+    from: &str,
+    to: &str,
+) {
+    if let (Some(state), Some(current)) = (previous.get(from), map.get_mut(to)) {
+        *current = state.clone();
+    }
+}
+
 pub async fn test_rules(
     file_name: &str,
-    settings: Arc<Settings>,
+    engine: Arc<RuleEngine>,
 ) -> Result<(), errors::HotdogError> {
     let file = File::open(file_name)
         .await
@@ -19,24 +194,30 @@ pub async fn test_rules(
     let mut lines = reader.lines();
     let mut number: u64 = 0;
 
+    let msg_only = engine.settings.rules.iter().all(|rule| {
+        rule.condition
+            .flatten()
+            .iter()
+            .all(|c| c.field.as_ref().map_or(true, |f| *f == Field::Msg))
+    });
+    if !msg_only {
+        error!("The test mode only has the message, so conditions on other fields will not match");
+    }
+
     while let Some(line) = lines.next().await {
         let line = line?;
         debug!("Testing the line: {}", line);
         number += 1;
         let mut matches: Vec<&Rule> = vec![];
-        let mut unused = HashMap::<String, String>::new();
-        let also_unused = HashMap::<String, jmespath::Expression>::new();
+        let mut unused = Variables::new();
+        let msg = parse::SyslogMessage {
+            msg: line,
+            ..Default::default()
+        };
 
-        for rule in settings.rules.iter() {
-            match rule.field {
-                Field::Msg => {
-                    if apply_rule(&rule, &line, &also_unused, &mut unused) {
-                        matches.push(rule);
-                    }
-                }
-                _ => {
-                    error!("The test mode will only work on `field: msg` rules");
-                }
+        for rule in engine.settings.rules.iter() {
+            if apply_rule(&rule, &msg, &engine.jmespaths, &mut unused) {
+                matches.push(rule);
             }
         }
 
@@ -50,24 +231,248 @@ pub async fn test_rules(
 
     Ok(())
 }
+
+/**
+ * Ensure that every condition either has a field with exactly one matcher, or is exactly one of
+ * `all`, `any`, or `not`. Conditions on structured data must also say which parameter to match
+ */
+pub fn validate_matchers(settings: &Settings) -> bool {
+    for rule in settings.rules.iter() {
+        for condition in rule.condition.flatten() {
+            let matchers = [
+                condition.regex.is_some(),
+                condition.jmespath.is_some(),
+                condition.compare.is_some(),
+            ];
+            let compounds = [
+                !condition.all.is_empty(),
+                !condition.any.is_empty(),
+                condition.not.is_some(),
+            ];
+            let matchers = matchers.iter().filter(|m| **m).count();
+            let compounds = compounds.iter().filter(|c| **c).count();
+
+            if condition.field.is_some() {
+                if matchers != 1 || compounds != 0 {
+                    error!("Conditions must have exactly one of `regex`, `jmespath`, or `compare`");
+                    return false;
+                }
+            } else if compounds != 1 || matchers != 0 {
+                error!("Conditions must have a `field`, or exactly one of `all`, `any`, or `not`");
+                return false;
+            }
+
+            if let Some(Field::Sd) = condition.field {
+                if condition.path.is_none() {
+                    error!("Conditions on the `sd` field must have a `path`");
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
 /**
- * Attempt to apply the given rule to the given field value, inserting the
- * necessary variables into the hash along the way.
+ * Ensure that every JSON pointer used by the actions refers to something within the message,
+ * e.g. `/meta/user`
+ */
+pub fn validate_pointers(settings: &Settings) -> bool {
+    for rule in settings.rules.iter() {
+        for action in rule.actions.iter() {
+            let pointers = match action {
+                Action::Merge {
+                    path: Some(path), ..
+                } => vec![path],
+                Action::Remove { path } => vec![path],
+                Action::Rename { from, to } => vec![from, to],
+                Action::Set { path, .. } => vec![path],
+                Action::Redact { paths, .. } => paths.iter().collect(),
+                _ => vec![],
+            };
+
+            for p in pointers {
+                if !pointer::is_valid(p) {
+                    error!("`{}` is not a valid JSON pointer, such as `/meta/user`", p);
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/**
+ * Ensure that every output action refers to an output which has been configured
+ */
+pub fn validate_outputs(settings: &Settings) -> bool {
+    for name in output_names(settings) {
+        if !settings.global.outputs.contains_key(name) {
+            error!("The output `{}` is not defined in global.outputs", name);
+            return false;
+        }
+    }
+    true
+}
+
+/**
+ * Return the name of the output of every output action in the rules
+ */
+pub fn output_names(settings: &Settings) -> Vec<&String> {
+    settings
+        .rules
+        .iter()
+        .flat_map(|rule| rule.actions.iter())
+        .filter_map(|action| match action {
+            Action::Output { name } => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/**
+ * Attempt to apply the given rule to the message, inserting the necessary variables into the
+ * hash along the way.
  *
  * If the rule matches, then this will return true
  */
 pub fn apply_rule(
     rule: &Rule,
+    msg: &parse::SyslogMessage,
+    jmespaths: &JmesPathExpressions,
+    hash: &mut Variables,
+) -> bool {
+    if !apply_condition(&rule.condition, msg, jmespaths, hash) {
+        return false;
+    }
+
+    if !rule.extract.is_empty() {
+        extract(&rule.extract, &msg.msg, jmespaths, hash);
+    }
+    true
+}
+
+/**
+ * Insert a variable for each of the named JMESPath extractions which has a result in the message
+ */
+fn extract(
+    extractions: &HashMap<String, String>,
     value: &str,
-    jmespaths: &crate::connection::JmesPathExpressions,
-    hash: &mut HashMap<String, String>,
+    jmespaths: &JmesPathExpressions,
+    hash: &mut Variables,
+) {
+    let data = match jmespath::Variable::from_json(value) {
+        Ok(data) => jmespath::Rcvar::new(data),
+        Err(_) => {
+            debug!("Unable to extract variables from a message which is not JSON");
+            return;
+        }
+    };
+
+    for (name, expression) in extractions.iter() {
+        if let Ok(result) = jmespaths[expression].search(data.clone()) {
+            if !result.is_null() {
+                hash.insert(name.to_string(), jmespath_text(&result).into());
+            }
+        }
+    }
+}
+
+/**
+ * Render the result of a JMESPath search for templates, strings are left as they are while
+ * everything else is rendered as JSON text
+ */
+fn jmespath_text(result: &jmespath::Variable) -> String {
+    match result.as_string() {
+        Some(string) => string.to_string(),
+        None => result.to_string(),
+    }
+}
+
+/**
+ * Evaluate the condition tree against the message.
+ *
+ * Variables captured by the matching conditions of an `all` or `any` are merged into the hash,
+ * while those of conditions which didn't match, or which are negated by `not`, are discarded
+ */
+fn apply_condition(
+    condition: &Condition,
+    msg: &parse::SyslogMessage,
+    jmespaths: &JmesPathExpressions,
+    hash: &mut Variables,
+) -> bool {
+    if !condition.all.is_empty() {
+        let mut captured = Variables::new();
+        let matches = condition
+            .all
+            .iter()
+            .all(|c| apply_condition(c, msg, jmespaths, &mut captured));
+        if matches {
+            hash.extend(captured);
+        }
+        matches
+    } else if !condition.any.is_empty() {
+        let mut matches = false;
+        for c in condition.any.iter() {
+            let mut captured = Variables::new();
+            if apply_condition(c, msg, jmespaths, &mut captured) {
+                hash.extend(captured);
+                matches = true;
+            }
+        }
+        matches
+    } else if let Some(negated) = &condition.not {
+        !apply_condition(negated, msg, jmespaths, &mut Variables::new())
+    } else if let Some(field) = &condition.field {
+        match field_value(field, condition, msg) {
+            Some(value) => apply_matcher(condition, field, &value, jmespaths, hash),
+            None => false,
+        }
+    } else {
+        false
+    }
+}
+
+/**
+ * Return the value of the field from the message, if it has one
+ */
+fn field_value(field: &Field, condition: &Condition, msg: &parse::SyslogMessage) -> Option<String> {
+    match field {
+        Field::Msg => Some(msg.msg.clone()),
+        Field::Appname => msg.appname.clone(),
+        Field::Hostname => msg.hostname.clone(),
+        Field::Severity => msg.severity.clone(),
+        Field::Facility => msg.facility.clone(),
+        Field::Procid => msg.procid.clone(),
+        Field::Msgid => msg.msgid.clone(),
+        Field::Timestamp => msg.timestamp.map(|t| t.to_rfc3339()),
+        Field::Sd => condition
+            .path
+            .as_ref()
+            .and_then(|path| parse::structured_data_param(&msg.structured_data, path))
+            .cloned(),
+    }
+}
+
+/**
+ * Attempt to apply the matcher of the condition to the given field value, inserting the
+ * necessary variables into the hash along the way.
+ *
+ * If the matcher matches, then this will return true
+ */
+fn apply_matcher(
+    condition: &Condition,
+    field: &Field,
+    value: &str,
+    jmespaths: &JmesPathExpressions,
+    hash: &mut Variables,
 ) -> bool {
     let mut rule_matches = false;
     /*
      * Check to see if we have a jmespath first
      *
      */
-    if let Some(expression) = &rule.jmespath {
+    if let Some(expression) = &condition.jmespath {
         let expr = &jmespaths[expression];
         if let Ok(data) = jmespath::Variable::from_json(value) {
             // Search the data with the compiled expression
@@ -75,22 +480,23 @@ pub fn apply_rule(
                 if !result.is_null() {
                     rule_matches = true;
                     debug!("jmespath rule matched, value: {}", result);
-                    if let Some(value) = result.as_string() {
-                        hash.insert("value".to_string(), value.to_string());
-                    } else {
-                        warn!("Unable to parse out the string value for {}, the `value` variable substitution will not be available,", result);
-                    }
+                    hash.insert("value".to_string(), jmespath_text(&result).into());
                 }
             }
         }
-    } else if let Some(regex) = &rule.regex {
+    } else if let Some(comparison) = &condition.compare {
+        rule_matches = compare(field, comparison, value);
+        if rule_matches {
+            hash.insert("value".to_string(), value.into());
+        }
+    } else if let Some(regex) = &condition.regex {
         if let Some(captures) = regex.captures(value) {
             rule_matches = true;
 
             for name in regex.capture_names() {
                 if let Some(name) = name {
                     if let Some(value) = captures.name(name) {
-                        hash.insert(name.to_string(), String::from(value.as_str()));
+                        hash.insert(name.to_string(), value.as_str().into());
                     }
                 }
             }
@@ -98,3 +504,722 @@ pub fn apply_rule(
     }
     rule_matches
 }
+
+/**
+ * Compare the value of the field against the comparison.
+ *
+ * Severities and facilities are compared by their numeric codes, so `<= warning` matches messages
+ * which are at least as severe as a warning. Other fields must be numbers, except for `==` and
+ * `!=` which fall back to comparing the strings
+ */
+fn compare(field: &Field, comparison: &Comparison, value: &str) -> bool {
+    let numeric = |s: &str| -> Option<f64> {
+        let code = match field {
+            Field::Severity => parse::severity_code(s),
+            Field::Facility => parse::facility_code(s),
+            _ => None,
+        };
+        code.map(f64::from).or_else(|| s.trim().parse::<f64>().ok())
+    };
+
+    match (numeric(value), numeric(&comparison.operand)) {
+        (Some(left), Some(right)) => match comparison.operator {
+            Operator::Lt => left < right,
+            Operator::Lte => left <= right,
+            Operator::Gt => left > right,
+            Operator::Gte => left >= right,
+            Operator::Eq => (left - right).abs() < f64::EPSILON,
+            Operator::Ne => (left - right).abs() >= f64::EPSILON,
+        },
+        _ => match comparison.operator {
+            Operator::Eq => value == comparison.operand,
+            Operator::Ne => value != comparison.operand,
+            _ => false,
+        },
+    }
+}
+
+/**
+ * Generate a unique identifier for the given template
+ */
+pub fn template_id_for(rule: &Rule, index: usize) -> String {
+    format!("{}-{}", rule.uuid, index)
+}
+
+/**
+ * Generate the name of the rule for metrics, which is its position in the rules unless it has
+ * been given a name
+ */
+pub fn name_for(rule: &Rule, index: usize) -> String {
+    match &rule.name {
+        Some(name) => name.to_string(),
+        None => index.to_string(),
+    }
+}
+
+/**
+ * Generate the identifier for the template of a single value of a typed merge
+ */
+pub fn typed_template_id(template_id: &str, pointer: &str) -> String {
+    format!("{}{}", template_id, pointer)
+}
+
+/**
+ * Generate the identifier for the template of one of the fields of an action, such as the topic
+ * of a forward
+ */
+pub fn field_template_id(template_id: &str, field: &str) -> String {
+    format!("{}#{}", template_id, field)
+}
+
+/**
+ * Return the JSON pointer and contents of every string value in the JSON
+ */
+pub fn string_values(json: &serde_json::Value) -> Vec<(String, &str)> {
+    fn walk<'a>(json: &'a serde_json::Value, pointer: String, values: &mut Vec<(String, &'a str)>) {
+        match json {
+            serde_json::Value::String(s) => values.push((pointer, s)),
+            serde_json::Value::Array(array) => {
+                for (index, value) in array.iter().enumerate() {
+                    walk(value, format!("{}/{}", pointer, index), values);
+                }
+            }
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter() {
+                    /*
+                     * Escape the key as required by RFC 6901
+                     */
+                    let key = key.replace('~', "~0").replace('/', "~1");
+                    walk(value, format!("{}/{}", pointer, key), values);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut values = vec![];
+    walk(json, String::new(), &mut values);
+    values
+}
+
+/**
+ * precompile_templates will register templates for all the actions from the settings which render
+ * templates, such as the topic of a Forward or the JSON of a Merge
+ *
+ * Will usually return a true, unless some setting parse failure occurred which is a critical
+ * failure for the daemon
+ */
+fn precompile_templates(hb: &mut Handlebars, settings: Arc<Settings>) -> bool {
+    for rule in settings.rules.iter() {
+        for index in 0..rule.actions.len() {
+            match &rule.actions[index] {
+                Action::Merge {
+                    json,
+                    typed: true,
+                    coerce,
+                    ..
+                } => {
+                    let template_id = template_id_for(rule, index);
+                    let values = string_values(json);
+
+                    for pointer in coerce.keys() {
+                        if !values.iter().any(|(p, _)| p == pointer) {
+                            error!("The merge has no string value to coerce at {}", pointer);
+                            return false;
+                        }
+                    }
+
+                    for (pointer, template) in values {
+                        let id = typed_template_id(&template_id, &pointer);
+                        if let Err(e) = hb.register_template_string(&id, template) {
+                            error!("Failed to register template! {}\n{}", e, template);
+                            return false;
+                        }
+                    }
+                }
+                Action::Merge {
+                    json_str, coerce, ..
+                } => {
+                    let template_id = template_id_for(rule, index);
+
+                    if !coerce.is_empty() {
+                        error!("Coercing values is only supported for merges which are `typed`");
+                        return false;
+                    }
+
+                    if let Some(template) = json_str {
+                        if let Err(e) = hb.register_template_string(&template_id, &template) {
+                            error!("Failed to register template! {}\n{}", e, template);
+                            return false;
+                        }
+                    } else {
+                        error!("Could not look up the json_str for a Merge action");
+                        return false;
+                    }
+                }
+                Action::Replace { template } => {
+                    let template_id = template_id_for(rule, index);
+                    if let Err(e) = hb.register_template_string(&template_id, &template) {
+                        error!("Failed to register template! {}\n{}", e, template);
+                        return false;
+                    }
+                }
+                Action::Forward {
+                    topic,
+                    key,
+                    headers,
+                    partition,
+                    ..
+                } => {
+                    let template_id = template_id_for(rule, index);
+                    let mut fields = vec![("topic".to_string(), topic)];
+                    if let Some(key) = key {
+                        fields.push(("key".to_string(), key));
+                    }
+                    if let Some(partition) = partition {
+                        fields.push(("partition".to_string(), partition));
+                    }
+                    for (name, value) in headers.iter() {
+                        fields.push((format!("headers/{}", name), value));
+                    }
+
+                    if !register_fields(hb, &template_id, &fields) {
+                        return false;
+                    }
+                }
+                Action::Dedupe {
+                    key,
+                    topic,
+                    summary,
+                    ..
+                } => {
+                    let template_id = template_id_for(rule, index);
+                    if let Err(e) = hb.register_template_string(&template_id, &summary) {
+                        error!("Failed to register template! {}\n{}", e, summary);
+                        return false;
+                    }
+
+                    let mut fields = vec![("topic".to_string(), topic)];
+                    if let Some(key) = key {
+                        fields.push(("key".to_string(), key));
+                    }
+                    if !register_fields(hb, &template_id, &fields) {
+                        return false;
+                    }
+                }
+                Action::RateLimit {
+                    key,
+                    overflow_topic,
+                    ..
+                } => {
+                    let mut fields = vec![];
+                    if let Some(key) = key {
+                        fields.push(("key".to_string(), key));
+                    }
+                    if let Some(topic) = overflow_topic {
+                        fields.push(("topic".to_string(), topic));
+                    }
+                    if !register_fields(hb, &template_id_for(rule, index), &fields) {
+                        return false;
+                    }
+                }
+                Action::Sample { key: Some(key), .. } => {
+                    let fields = vec![("key".to_string(), key)];
+                    if !register_fields(hb, &template_id_for(rule, index), &fields) {
+                        return false;
+                    }
+                }
+                Action::Set { value, .. } => {
+                    let template_id = template_id_for(rule, index);
+                    for (pointer, template) in string_values(value) {
+                        let id = typed_template_id(&template_id, &pointer);
+                        if let Err(e) = hb.register_template_string(&id, template) {
+                            error!("Failed to register template! {}\n{}", e, template);
+                            return false;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    true
+}
+
+/**
+ * Register the templates for the named fields of the action with the given template id
+ */
+fn register_fields(hb: &mut Handlebars, template_id: &str, fields: &[(String, &String)]) -> bool {
+    for (field, template) in fields.iter() {
+        let id = field_template_id(template_id, field);
+        if let Err(e) = hb.register_template_string(&id, template) {
+            error!("Failed to register template! {}\n{}", e, template);
+            return false;
+        }
+    }
+    true
+}
+
+/**
+ * precompile_jmespath will pre-generate all the necessary JMESPath::Variable objects from the
+ * configuration file and shove thoe in the map given to it
+ */
+fn precompile_jmespath(map: &mut JmesPathExpressions, settings: Arc<Settings>) -> bool {
+    for rule in settings.rules.iter() {
+        let expressions = rule
+            .condition
+            .flatten()
+            .into_iter()
+            .filter_map(|condition| condition.jmespath.as_ref())
+            .chain(rule.extract.values());
+
+        for expression in expressions {
+            if !map.contains_key(expression) {
+                if let Ok(compiled) = jmespath::compile(&expression) {
+                    map.insert(expression.to_string(), compiled);
+                } else {
+                    error!("Failed to compile the JMESPath expression: {}", expression);
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/**
+ * precompile_redactors will compile the detectors and patterns of every redact action from the
+ * settings into the map given to it
+ */
+fn precompile_redactors(map: &mut Redactors, settings: &Settings) -> bool {
+    for rule in settings.rules.iter() {
+        for index in 0..rule.actions.len() {
+            if let Action::Redact {
+                detectors,
+                patterns,
+                mask,
+                hash_key,
+                ..
+            } = &rule.actions[index]
+            {
+                match Redactor::new(detectors, patterns, mask, hash_key.as_deref()) {
+                    Ok(redactor) => {
+                        map.insert(template_id_for(rule, index), redactor);
+                    }
+                    Err(e) => {
+                        error!("Failed to compile the redact patterns: {}", e);
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+    true
+}
+
+/**
+ * precompile_samplers will create the samplers for every sample action from the settings in the
+ * map given to it
+ */
+fn precompile_samplers(map: &mut Samplers, settings: &Settings) -> bool {
+    for rule in settings.rules.iter() {
+        for index in 0..rule.actions.len() {
+            if let Action::Sample { percent, every, .. } = &rule.actions[index] {
+                match Sampler::new(*percent, *every) {
+                    Ok(sampler) => {
+                        map.insert(template_id_for(rule, index), Arc::new(sampler));
+                    }
+                    Err(e) => {
+                        error!("Invalid sample action: {}", e);
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+    true
+}
+
+/**
+ * precompile_rate_limiters will create the token buckets for every rate limit action from the
+ * settings in the map given to it
+ */
+fn precompile_rate_limiters(map: &mut RateLimiters, settings: &Settings) -> bool {
+    for rule in settings.rules.iter() {
+        for index in 0..rule.actions.len() {
+            if let Action::RateLimit {
+                per_second, burst, ..
+            } = &rule.actions[index]
+            {
+                match RateLimiter::new(*per_second, *burst) {
+                    Ok(limiter) => {
+                        map.insert(template_id_for(rule, index), Arc::new(limiter));
+                    }
+                    Err(e) => {
+                        error!("Invalid rate limit action: {}", e);
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+    true
+}
+
+/**
+ * precompile_deduplicators will create the windows for every dedupe action from the settings in
+ * the map given to it
+ */
+fn precompile_deduplicators(map: &mut Deduplicators, settings: &Settings) {
+    for rule in settings.rules.iter() {
+        for index in 0..rule.actions.len() {
+            if let Action::Dedupe { window_ms, .. } = &rule.actions[index] {
+                map.insert(
+                    template_id_for(rule, index),
+                    Arc::new(Deduplicator::new(std::time::Duration::from_millis(
+                        *window_ms,
+                    ))),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precompile_templates_merge() {
+        let mut hb = Handlebars::new();
+        let settings = Arc::new(load("test/configs/single-rule-with-merge.yml"));
+        // Assuming that we're going to register the template with this id
+        let template_id = format!("{}-{}", settings.rules[0].uuid, 0);
+
+        let result = precompile_templates(&mut hb, settings.clone());
+        assert!(result);
+        assert!(hb.has_template(&template_id));
+    }
+
+    #[test]
+    fn test_precompile_templates_forward() {
+        let mut hb = Handlebars::new();
+        let settings = Arc::new(load("test/configs/single-rule-with-forward-key.yml"));
+        let template_id = template_id_for(&settings.rules[0], 0);
+
+        assert!(precompile_templates(&mut hb, settings.clone()));
+        for field in &["topic", "key", "headers/app", "partition"] {
+            assert!(hb.has_template(&field_template_id(&template_id, field)));
+        }
+    }
+
+    #[test]
+    fn test_precompile_templates_replace() {
+        let mut hb = Handlebars::new();
+        let settings = Arc::new(load("test/configs/single-rule-with-replace.yml"));
+        // Assuming that we're going to register the template with this id
+        let template_id = format!("{}-{}", settings.rules[0].uuid, 0);
+
+        let result = precompile_templates(&mut hb, settings.clone());
+        assert!(result);
+        assert!(hb.has_template(&template_id));
+    }
+
+    #[test]
+    fn test_precompile_templates_typed_merge() {
+        let mut hb = Handlebars::new();
+        let settings = Arc::new(load("test/configs/single-rule-with-typed-merge.yml"));
+        let template_id = template_id_for(&settings.rules[0], 0);
+
+        assert!(precompile_templates(&mut hb, settings));
+        assert!(hb.has_template(&typed_template_id(&template_id, "/code")));
+        assert!(hb.has_template(&typed_template_id(&template_id, "/meta/ratio")));
+    }
+
+    #[test]
+    fn test_validate_pointers() {
+        let settings = load("test/configs/single-rule-with-json-actions.yml");
+        assert!(validate_pointers(&settings));
+    }
+
+    #[test]
+    fn test_validate_pointers_invalid() {
+        let mut settings = load("test/configs/single-rule-with-json-actions.yml");
+        settings.rules[0].actions[0] = Action::Remove {
+            path: "password".to_string(),
+        };
+        assert!(!validate_pointers(&settings));
+    }
+
+    #[test]
+    fn test_precompile_redactors() {
+        let settings = load("test/configs/single-rule-with-redact.yml");
+        let mut redactors = Redactors::new();
+
+        assert!(precompile_redactors(&mut redactors, &settings));
+        assert!(redactors.contains_key(&template_id_for(&settings.rules[0], 0)));
+        assert!(redactors.contains_key(&template_id_for(&settings.rules[0], 1)));
+    }
+
+    #[test]
+    fn test_precompile_redactors_invalid() {
+        let mut settings = load("test/configs/single-rule-with-redact.yml");
+        let mut patterns = HashMap::new();
+        patterns.insert("broken".to_string(), "(".to_string());
+        settings.rules[0].actions[0] = Action::Redact {
+            detectors: vec![],
+            patterns,
+            paths: vec![],
+            mask: "".to_string(),
+            hash_key: None,
+        };
+
+        assert!(!precompile_redactors(&mut Redactors::new(), &settings));
+    }
+
+    #[test]
+    fn test_precompile_samplers() {
+        let settings = load("test/configs/rules-with-drop-and-sample.yml");
+        let mut samplers = Samplers::new();
+
+        assert!(precompile_samplers(&mut samplers, &settings));
+        assert!(samplers.contains_key(&template_id_for(&settings.rules[0], 0)));
+    }
+
+    #[test]
+    fn test_precompile_samplers_invalid() {
+        let mut settings = load("test/configs/rules-with-drop-and-sample.yml");
+        settings.rules[0].actions[0] = Action::Sample {
+            percent: Some(10.0),
+            every: Some(10),
+            key: None,
+        };
+
+        assert!(!precompile_samplers(&mut Samplers::new(), &settings));
+    }
+
+    #[test]
+    fn test_precompile_rate_limiters() {
+        let settings = load("test/configs/rules-with-rate-limit.yml");
+        let mut limiters = RateLimiters::new();
+
+        assert!(precompile_rate_limiters(&mut limiters, &settings));
+        assert!(limiters.contains_key(&template_id_for(&settings.rules[0], 0)));
+        assert!(limiters.contains_key(&template_id_for(&settings.rules[1], 0)));
+    }
+
+    #[test]
+    fn test_precompile_rate_limiters_invalid() {
+        let mut settings = load("test/configs/rules-with-rate-limit.yml");
+        settings.rules[0].actions[0] = Action::RateLimit {
+            per_second: 0.0,
+            burst: None,
+            key: None,
+            overflow_topic: None,
+        };
+
+        assert!(!precompile_rate_limiters(
+            &mut RateLimiters::new(),
+            &settings
+        ));
+    }
+
+    #[test]
+    fn test_precompile_deduplicators() {
+        let settings = Arc::new(load("test/configs/single-rule-with-dedupe.yml"));
+        let template_id = template_id_for(&settings.rules[0], 0);
+        let mut deduplicators = Deduplicators::new();
+        let mut hb = Handlebars::new();
+
+        precompile_deduplicators(&mut deduplicators, &settings);
+        assert!(deduplicators.contains_key(&template_id));
+        assert!(precompile_templates(&mut hb, settings));
+        assert!(hb.has_template(&template_id));
+    }
+
+    #[test]
+    fn test_name_for() {
+        let settings = load("test/configs/rules-with-drop-and-sample.yml");
+        assert_eq!("sampled", name_for(&settings.rules[0], 0));
+        assert_eq!("2", name_for(&settings.rules[2], 2));
+    }
+
+    #[test]
+    fn test_string_values() {
+        let json = serde_json::json!({"a": "1", "b/c": ["2", 3], "d": {"e": "4"}});
+        let mut values = string_values(&json);
+        values.sort();
+        assert_eq!(
+            vec![
+                ("/a".to_string(), "1"),
+                ("/b~1c/0".to_string(), "2"),
+                ("/d/e".to_string(), "4"),
+            ],
+            values
+        );
+    }
+
+    #[test]
+    fn test_precompile_jmespath() {
+        let settings = Arc::new(load("test/configs/single-rule-with-merge.yml"));
+        let mut map = JmesPathExpressions::new();
+        let result = precompile_jmespath(&mut map, settings.clone());
+        assert!(result);
+        let expected = settings.rules[0].condition.jmespath.as_ref().unwrap();
+        assert!(map.contains_key(expected));
+    }
+
+    #[test]
+    fn test_precompile_jmespath_extract() {
+        let settings = Arc::new(load("test/configs/single-rule-with-extract.yml"));
+        let mut map = JmesPathExpressions::new();
+        assert!(precompile_jmespath(&mut map, settings));
+        assert!(map.contains_key("meta.topic"));
+        assert!(map.contains_key("user.id"));
+        assert!(map.contains_key("tags"));
+    }
+
+    #[test]
+    fn test_apply_rule_extract() {
+        let settings = Arc::new(load("test/configs/single-rule-with-extract.yml"));
+        let engine = RuleEngine::new(settings).expect("Failed to compile the rules");
+        let msg = parse::SyslogMessage {
+            msg: r#"{"meta":{"topic":"audit"},"user":{"id":42},"tags":["a","b"]}"#.to_string(),
+            ..Default::default()
+        };
+        let mut hash = Variables::new();
+
+        assert!(apply_rule(
+            &engine.settings.rules[0],
+            &msg,
+            &engine.jmespaths,
+            &mut hash
+        ));
+        assert_eq!(Some(&serde_json::Value::from("audit")), hash.get("value"));
+        assert_eq!(Some(&serde_json::Value::from("42")), hash.get("user"));
+        assert_eq!(
+            Some(&serde_json::Value::from(r#"["a","b"]"#)),
+            hash.get("tags")
+        );
+        assert_eq!(None, hash.get("missing"));
+    }
+
+    #[test]
+    fn test_precompile_jmespath_baddata() {
+        let settings = Arc::new(load("test/configs/single-rule-with-invalid-jmespath.yml"));
+        let mut map = JmesPathExpressions::new();
+        let result = precompile_jmespath(&mut map, settings.clone());
+        assert!(!result);
+    }
+
+    #[test]
+    fn test_rule_engine() {
+        let settings = Arc::new(load("hotdog.yml"));
+        let engine = RuleEngine::new(settings.clone()).expect("Failed to compile the rules");
+        assert!(Arc::ptr_eq(&settings, &engine.settings));
+        assert_eq!(1, engine.jmespaths.len());
+    }
+
+    #[test]
+    fn test_validate_outputs() {
+        let settings = load("test/configs/single-rule-with-output.yml");
+        assert!(validate_outputs(&settings));
+    }
+
+    #[test]
+    fn test_validate_outputs_undefined() {
+        let mut settings = load("test/configs/single-rule-with-output.yml");
+        settings.global.outputs.remove("audit");
+        assert!(!validate_outputs(&settings));
+    }
+
+    #[test]
+    fn test_validate_matchers() {
+        let settings = load("test/configs/rules-with-sd-and-compare.yml");
+        assert!(validate_matchers(&settings));
+    }
+
+    #[test]
+    fn test_validate_matchers_sd_without_path() {
+        let mut settings = load("test/configs/rules-with-sd-and-compare.yml");
+        settings.rules[0].condition.path = None;
+        assert!(!validate_matchers(&settings));
+    }
+
+    #[test]
+    fn test_validate_matchers_multiple() {
+        let mut settings = load("test/configs/rules-with-sd-and-compare.yml");
+        settings.rules[0].condition.compare = "== 1".parse().ok();
+        assert!(!validate_matchers(&settings));
+    }
+
+    #[test]
+    fn test_validate_matchers_compound() {
+        let settings = load("test/configs/rule-with-conditions.yml");
+        assert!(validate_matchers(&settings));
+    }
+
+    #[test]
+    fn test_apply_rule_discards_unmatched_captures() {
+        let settings = Arc::new(load("test/configs/rule-with-conditions.yml"));
+        let engine = RuleEngine::new(settings).expect("Failed to compile the rules");
+        let msg = parse::SyslogMessage {
+            msg: "status=503".to_string(),
+            appname: Some("apache".to_string()),
+            ..Default::default()
+        };
+        let mut hash = Variables::new();
+
+        assert!(!apply_rule(
+            &engine.settings.rules[0],
+            &msg,
+            &engine.jmespaths,
+            &mut hash
+        ));
+        assert!(hash.is_empty());
+    }
+
+    #[test]
+    fn test_compare_severity() {
+        let comparison: Comparison = "<= warning".parse().expect("Failed to parse");
+        assert!(compare(&Field::Severity, &comparison, "err"));
+        assert!(compare(&Field::Severity, &comparison, "warning"));
+        assert!(!compare(&Field::Severity, &comparison, "info"));
+    }
+
+    #[test]
+    fn test_compare_numbers() {
+        let comparison: Comparison = "> 1000".parse().expect("Failed to parse");
+        assert!(compare(&Field::Procid, &comparison, "4321"));
+        assert!(!compare(&Field::Procid, &comparison, "42"));
+        assert!(!compare(&Field::Procid, &comparison, "sshd"));
+    }
+
+    #[test]
+    fn test_compare_strings() {
+        let comparison: Comparison = "!= ID47".parse().expect("Failed to parse");
+        assert!(compare(&Field::Msgid, &comparison, "ID48"));
+        assert!(!compare(&Field::Msgid, &comparison, "ID47"));
+    }
+
+    #[test]
+    fn test_rule_engine_does_not_escape() {
+        let settings = Arc::new(load("test/configs/single-rule-with-typed-merge.yml"));
+        let engine = RuleEngine::new(settings.clone()).expect("Failed to compile the rules");
+        let template_id = template_id_for(&settings.rules[0], 0);
+
+        let mut variables = Variables::new();
+        variables.insert("value".to_string(), r#"say "hi" & bye"#.into());
+        let rendered = engine
+            .hb
+            .render(&typed_template_id(&template_id, "/code"), &variables)
+            .expect("Failed to render");
+        assert_eq!(r#"say "hi" & bye"#, rendered);
+    }
+
+    #[test]
+    fn test_rule_engine_baddata() {
+        let settings = Arc::new(load("test/configs/single-rule-with-invalid-jmespath.yml"));
+        assert!(RuleEngine::new(settings).is_none());
+    }
+}
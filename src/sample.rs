@@ -0,0 +1,127 @@
+use crate::partition;
+/**
+ * The sample module contains the Sampler which decides which of the messages matching a rule's
+ * sample action are kept.
+ *
+ * Without a key the kept messages are spread evenly, e.g. every tenth message for `percent: 10`.
+ * With a key the decision is made by hashing it, so every message with the same key is either
+ * kept or discarded, no matter which connection or hotdog instance receives it
+ */
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, PartialEq)]
+enum Rate {
+    Percent(f64),
+    Every(u64),
+}
+
+#[derive(Debug)]
+pub struct Sampler {
+    rate: Rate,
+    /**
+     * The number of unkeyed messages seen so far
+     */
+    seen: AtomicU64,
+}
+
+impl Sampler {
+    /**
+     * Create the sampler for exactly one of `percent`, which must be between 0 and 100, or `every`,
+     * which must be at least 1
+     */
+    pub fn new(percent: Option<f64>, every: Option<u64>) -> Result<Self, String> {
+        let rate = match (percent, every) {
+            (Some(percent), None) if (0.0..=100.0).contains(&percent) => Rate::Percent(percent),
+            (Some(percent), None) => {
+                return Err(format!("{} is not a percentage between 0 and 100", percent))
+            }
+            (None, Some(every)) if every > 0 => Rate::Every(every),
+            (None, Some(_)) => return Err("`every` must be at least 1".to_string()),
+            _ => return Err("Exactly one of `percent` or `every` must be given".to_string()),
+        };
+
+        Ok(Sampler {
+            rate,
+            seen: AtomicU64::new(0),
+        })
+    }
+
+    /**
+     * Returns true if the message, with the rendered key if there is one, should be kept
+     */
+    pub fn sample(&self, key: Option<&str>) -> bool {
+        match key {
+            Some(key) => {
+                let hash = partition::crc32(key.as_bytes()) as u64;
+                match self.rate {
+                    Rate::Percent(percent) => ((hash % 10_000) as f64) < percent * 100.0,
+                    Rate::Every(every) => hash % every == 0,
+                }
+            }
+            None => {
+                let seen = self.seen.fetch_add(1, Ordering::Relaxed);
+                match self.rate {
+                    /*
+                     * Keep the message whenever it carries the running total of kept messages
+                     * over the next whole number
+                     */
+                    Rate::Percent(percent) => {
+                        let kept = |n: u64| (n as f64 * percent / 100.0).floor();
+                        kept(seen + 1) > kept(seen)
+                    }
+                    Rate::Every(every) => seen % every == 0,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_invalid() {
+        assert!(Sampler::new(None, None).is_err());
+        assert!(Sampler::new(Some(10.0), Some(10)).is_err());
+        assert!(Sampler::new(Some(101.0), None).is_err());
+        assert!(Sampler::new(None, Some(0)).is_err());
+    }
+
+    #[test]
+    fn test_sample_every() {
+        let sampler = Sampler::new(None, Some(3)).expect("Failed to create the sampler");
+        let kept: Vec<bool> = (0..6).map(|_| sampler.sample(None)).collect();
+        assert_eq!(vec![true, false, false, true, false, false], kept);
+    }
+
+    #[test]
+    fn test_sample_percent() {
+        let sampler = Sampler::new(Some(25.0), None).expect("Failed to create the sampler");
+        let kept = (0..100).filter(|_| sampler.sample(None)).count();
+        assert_eq!(25, kept);
+    }
+
+    #[test]
+    fn test_sample_percent_bounds() {
+        let all = Sampler::new(Some(100.0), None).expect("Failed to create the sampler");
+        let none = Sampler::new(Some(0.0), None).expect("Failed to create the sampler");
+
+        assert!((0..10).all(|_| all.sample(None)));
+        assert!((0..10).all(|_| !none.sample(None)));
+        assert!(all.sample(Some("coconut")));
+        assert!(!none.sample(Some("coconut")));
+    }
+
+    #[test]
+    fn test_sample_keyed_is_consistent() {
+        let sampler = Sampler::new(Some(50.0), None).expect("Failed to create the sampler");
+        let keys: Vec<String> = (0..100).map(|i| format!("host-{}", i)).collect();
+
+        let first: Vec<bool> = keys.iter().map(|k| sampler.sample(Some(k))).collect();
+        let second: Vec<bool> = keys.iter().map(|k| sampler.sample(Some(k))).collect();
+        assert_eq!(first, second);
+        assert!(first.iter().any(|kept| *kept));
+        assert!(first.iter().any(|kept| !*kept));
+    }
+}
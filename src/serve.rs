@@ -1,7 +1,10 @@
 use crate::connection::*;
 use crate::errors;
-use crate::kafka::Kafka;
-use crate::settings::Settings;
+use crate::kafka::{Kafka, KafkaSender};
+use crate::output::Outputs;
+use crate::reload::ReloadableSettings;
+use crate::settings::{Listen, Settings};
+use crate::spool::Spool;
 use crate::status;
 /**
  * The serve module is responsible for general syslog over TCP serving functionality
@@ -18,13 +21,21 @@ use log::*;
 
 pub struct ServerState {
     /**
-     * A reference to the global Settings object for all configuration information
+     * A reference to the reloadable Settings object for all configuration information
      */
-    pub settings: Arc<Settings>,
+    pub settings: Arc<ReloadableSettings>,
     /**
      * A Sender for sending statistics to the status handler
      */
     pub stats: Sender<status::Statistic>,
+    /**
+     * The outputs shared between all listeners
+     */
+    pub outputs: Arc<Outputs>,
+    /**
+     * The configuration of the listener this server is responsible for
+     */
+    pub listen: Listen,
 }
 
 /**
@@ -60,11 +71,12 @@ pub trait Server {
         connection: Connection,
         stats: Sender<status::Statistic>,
     ) -> Result<(), std::io::Error> {
-        debug!("Accepting from: {}", stream.peer_addr()?);
+        let peer = stream.peer_addr()?;
+        debug!("Accepting from: {}", peer);
         let reader = BufReader::new(stream);
 
         task::spawn(async move {
-            if let Err(e) = connection.read_logs(reader).await {
+            if let Err(e) = connection.read_logs(reader, Some(peer)).await {
                 error!("Failure occurred while read_logs executed: {:?}", e);
             }
 
@@ -87,23 +99,6 @@ pub trait Server {
             .next()
             .unwrap_or_else(|| panic!("Could not turn {:?} into a listenable interface", addr));
 
-        let mut kafka = Kafka::new(state.settings.global.kafka.buffer, state.stats.clone());
-
-        if !kafka.connect(
-            &state.settings.global.kafka.conf,
-            Some(state.settings.global.kafka.timeout_ms),
-        ) {
-            error!("Cannot start hotdog without a workable broker connection");
-            return Err(errors::HotdogError::KafkaConnectError);
-        }
-
-        let sender = kafka.get_sender();
-
-        task::spawn(async move {
-            debug!("Starting Kafka sendloop");
-            kafka.sendloop().await;
-        });
-
         self.bootstrap(&state)?;
 
         let listener = TcpListener::bind(addr).await?;
@@ -115,8 +110,14 @@ pub trait Server {
 
             state.stats.send((status::Stats::ConnectionCount, 1)).await;
 
-            let connection =
-                Connection::new(state.settings.clone(), sender.clone(), state.stats.clone());
+            let connection = Connection::new(
+                state.settings.clone(),
+                state.outputs.clone(),
+                state.stats.clone(),
+                state.listen.framing,
+                state.listen.max_frame_size,
+                state.listen.multiline.clone(),
+            );
 
             if let Err(e) = self.handle_connection(stream, connection, state.stats.clone()) {
                 error!("Failed to handle_connection properly: {:?}", e);
@@ -128,3 +129,39 @@ pub trait Server {
         Ok(())
     }
 }
+
+/**
+ * Connect to the configured Kafka brokers and spawn the sendloop, returning the Sender which
+ * every listener should use to pass messages along to Kafka
+ */
+pub fn start_kafka(
+    settings: &Settings,
+    stats: Sender<status::Statistic>,
+) -> Result<KafkaSender, errors::HotdogError> {
+    let mut kafka = Kafka::new(settings.global.kafka.buffer, stats.clone());
+
+    if let Some(spool) = &settings.global.kafka.spool {
+        info!(
+            "Spooling undeliverable messages to {}",
+            spool.path.display()
+        );
+        kafka.spool_to(Arc::new(Spool::open(spool, stats)?));
+    }
+
+    if !kafka.connect(
+        &settings.global.kafka.conf,
+        Some(settings.global.kafka.timeout_ms),
+    ) {
+        error!("Cannot start hotdog without a workable broker connection");
+        return Err(errors::HotdogError::KafkaConnectError);
+    }
+
+    let sender = kafka.get_sender();
+
+    task::spawn(async move {
+        debug!("Starting Kafka sendloop");
+        kafka.sendloop().await;
+    });
+
+    Ok(sender)
+}
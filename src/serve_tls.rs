@@ -52,7 +52,8 @@ impl Server for TlsServer {
         connection: Connection,
         stats: Sender<status::Statistic>,
     ) -> Result<(), std::io::Error> {
-        debug!("Accepting from: {}", stream.peer_addr()?);
+        let peer = stream.peer_addr()?;
+        debug!("Accepting from: {}", peer);
 
         // Calling `acceptor.accept` will start the TLS handshake
         let handshake = self.acceptor.accept(stream);
@@ -64,7 +65,7 @@ impl Server for TlsServer {
                 Ok(tls_stream) => {
                     let reader = BufReader::new(tls_stream);
 
-                    if let Err(e) = connection.read_logs(reader).await {
+                    if let Err(e) = connection.read_logs(reader, Some(peer)).await {
                         error!("Failure occurred while read_logs executed: {:?}", e);
                     }
                 }
@@ -83,7 +84,7 @@ impl Server for TlsServer {
  * Generate the default ServerConfig needed for rustls to work properly in server mode
  */
 fn load_tls_config(state: &ServerState) -> io::Result<ServerConfig> {
-    match &state.settings.global.listen.tls {
+    match &state.listen.tls {
         TlsType::CertAndKey { cert, key, ca } => {
             let certs = load_certs(cert.as_path())?;
             let mut keys = load_keys(key.as_path())?;
@@ -0,0 +1,51 @@
+use crate::connection::*;
+use crate::errors;
+use crate::serve::*;
+/**
+ * This module is responsible for receiving syslog messages over UDP, where every datagram is
+ * expected to contain a single syslog message
+ */
+use async_std::net::{ToSocketAddrs, UdpSocket};
+use async_trait::async_trait;
+use log::*;
+
+pub struct UdpServer {}
+
+#[async_trait]
+impl Server for UdpServer {
+    /**
+     * There are no connections to accept with UDP, so a single Connection is created which will
+     * process every datagram received on the socket
+     */
+    async fn accept_loop(
+        &mut self,
+        addr: &str,
+        state: ServerState,
+    ) -> Result<(), errors::HotdogError> {
+        let mut addr = addr.to_socket_addrs().await?;
+        let addr = addr
+            .next()
+            .unwrap_or_else(|| panic!("Could not turn {:?} into a listenable interface", addr));
+
+        self.bootstrap(&state)?;
+
+        let socket = UdpSocket::bind(addr).await?;
+        debug!("Receiving datagrams on: {}", socket.local_addr()?);
+
+        let connection = Connection::new(
+            state.settings.clone(),
+            state.outputs.clone(),
+            state.stats.clone(),
+            state.listen.framing,
+            state.listen.max_frame_size,
+            state.listen.multiline.clone(),
+        );
+        connection
+            .read_datagrams(socket, state.listen.datagram_size)
+            .await?;
+
+        self.shutdown(&state)?;
+
+        Ok(())
+    }
+}
@@ -10,19 +10,29 @@ use std::time::Duration;
 use uuid::Uuid;
 
 pub fn load(file: &str) -> Settings {
-    let conf = load_configuration(file);
-    let mut settings: Settings = conf
-        .try_into()
-        .expect("Failed to parse the configuration file");
+    try_load(file).expect("Failed to parse the configuration file")
+}
+
+/**
+ * Load the configuration file, returning an error rather than panicking if it cannot be loaded.
+ *
+ * This is necessary for reloading the configuration in a running daemon
+ */
+pub fn try_load(file: &str) -> Result<Settings, config::ConfigError> {
+    let conf = load_configuration(file)?;
+    let mut settings: Settings = conf.try_into()?;
+    settings.validate().map_err(config::ConfigError::Message)?;
     settings.populate_caches();
-    settings
+    Ok(settings)
 }
 
-fn load_configuration(file: &str) -> config::Config {
+fn load_configuration(file: &str) -> Result<config::Config, config::ConfigError> {
     let file_path = Path::new(file);
 
-    if file_path.extension().unwrap() != "yml" {
-        panic!("The configuration file must end with .yml");
+    if file_path.extension().map_or(true, |ext| ext != "yml") {
+        return Err(config::ConfigError::Message(
+            "The configuration file must end with .yml".to_string(),
+        ));
     }
 
     debug!("Loading configuration from {}", file);
@@ -36,15 +46,26 @@ fn load_configuration(file: &str) -> config::Config {
      * Each layer overriding properties from the last
      */
     let mut conf = config::Config::default();
-    conf.merge(config::File::with_name(file))
-        .unwrap()
-        .merge(config::Environment::with_prefix("HOTDOG"))
-        .unwrap();
+    conf.merge(config::File::with_name(file))?
+        .merge(config::Environment::with_prefix("HOTDOG"))?;
 
-    let _port: u64 = conf
-        .get("global.listen.port")
-        .expect("Configuration had no `global.listen.port` setting");
-    conf
+    if conf.get_array("global.listen").is_err() {
+        /*
+         * Configurations from before multiple listeners were supported have a single listener,
+         * which is treated as a list of one
+         */
+        match conf.get_table("global.listen") {
+            Ok(listen) => {
+                conf.set("global.listen", vec![listen])?;
+            }
+            Err(_) => {
+                return Err(config::ConfigError::Message(
+                    "Configuration had no `global.listen` list of listeners".to_string(),
+                ));
+            }
+        }
+    }
+    Ok(conf)
 }
 
 /**
@@ -52,52 +73,395 @@ fn load_configuration(file: &str) -> config::Config {
  *
  * They should be camel-cased in the yaml configuration
  */
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum Field {
     Severity,
     Facility,
     Hostname,
     Appname,
+    Procid,
+    Msgid,
+    /**
+     * The timestamp from the syslog header, matched in its RFC 3339 form
+     */
+    Timestamp,
+    /**
+     * A structured data parameter, selected by the rule's `path`
+     */
+    Sd,
     Msg,
 }
 
-#[derive(Debug, Deserialize)]
+/**
+ * The operators supported by the `compare` matcher
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Eq,
+    Ne,
+}
+
+/**
+ * A Comparison is parsed from strings such as `<= warning` or `> 1000`
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub operator: Operator,
+    pub operand: String,
+}
+
+impl std::str::FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        /*
+         * Two character operators must be checked first so that `<=` isn't read as `<`
+         */
+        let operators = [
+            ("<=", Operator::Lte),
+            (">=", Operator::Gte),
+            ("==", Operator::Eq),
+            ("!=", Operator::Ne),
+            ("<", Operator::Lt),
+            (">", Operator::Gt),
+        ];
+
+        for (prefix, operator) in operators.iter() {
+            if s.starts_with(prefix) {
+                let operand = s[prefix.len()..].trim();
+                if operand.is_empty() {
+                    break;
+                }
+                return Ok(Comparison {
+                    operator: *operator,
+                    operand: operand.to_string(),
+                });
+            }
+        }
+        Err(format!("Invalid comparison `{}`", s))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Comparison {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum Action {
     Forward {
         topic: String,
+        /**
+         * An optional template for the key of the produced Kafka message
+         */
+        #[serde(default = "default_none")]
+        key: Option<String>,
+        /**
+         * Templates for the headers of the produced Kafka message
+         */
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        /**
+         * An optional template for the partition the message should be produced to
+         */
+        #[serde(default = "default_none")]
+        partition: Option<String>,
+        /**
+         * The strategy for selecting the partition from the key when no partition is set
+         */
+        #[serde(default = "default_none")]
+        partitioner: Option<Partitioner>,
+    },
+    /**
+     * Replace the output buffer with a JSON document of the entire parsed message, see
+     * envelope::Envelope
+     */
+    Envelope,
+    /**
+     * Suppress the messages repeating the first with the same key within the window, forwarding
+     * a summary of how many were suppressed to `topic` once the window closes
+     */
+    Dedupe {
+        window_ms: u64,
+        /**
+         * An optional template for the key of the messages considered identical, which defaults
+         * to the output buffer
+         */
+        #[serde(default = "default_none")]
+        key: Option<String>,
+        topic: String,
+        /**
+         * The template for the summary, which has the `repeats` variable in addition to those of
+         * the first message
+         */
+        #[serde(default = "dedupe_summary_default")]
+        summary: String,
     },
+    /**
+     * Discard the message, stopping the processing of actions and rules
+     */
+    Drop,
     Merge {
         json: Value,
         #[serde(default = "default_none")]
         json_str: Option<String>,
+        /**
+         * Substitute the variables into each string value of the JSON, rather than rendering the
+         * entire JSON as a single template, which preserves types and cannot produce invalid JSON
+         */
+        #[serde(default)]
+        typed: bool,
+        /**
+         * The types to convert the rendered values at the given JSON pointers to when `typed`,
+         * e.g. `/status: int`
+         */
+        #[serde(default)]
+        coerce: HashMap<String, Coercion>,
+        /**
+         * The JSON pointer to merge at, rather than the root of the message
+         */
+        #[serde(default = "default_none")]
+        path: Option<String>,
+    },
+    /**
+     * Send the current output buffer to one of the configured outputs, unlike forward this does
+     * not stop the processing of actions
+     */
+    Output {
+        name: String,
+    },
+    /**
+     * Remove the field at the JSON pointer from the message
+     */
+    Remove {
+        path: String,
+    },
+    /**
+     * Move the field at the `from` JSON pointer to the `to` JSON pointer
+     */
+    Rename {
+        from: String,
+        to: String,
+    },
+    /**
+     * Limit the rate of messages continuing with the following actions with a token bucket,
+     * discarding the messages over the limit or forwarding them to `overflow_topic`
+     */
+    RateLimit {
+        per_second: f64,
+        /**
+         * The number of messages which may be sent at once, defaulting to a second's worth
+         */
+        #[serde(default = "default_none")]
+        burst: Option<f64>,
+        /**
+         * An optional template, such as `{{hostname}}`, whose rendered values each get their own
+         * bucket
+         */
+        #[serde(default = "default_none")]
+        key: Option<String>,
+        /**
+         * An optional template for the topic the messages over the limit are forwarded to
+         */
+        #[serde(default = "default_none")]
+        overflow_topic: Option<String>,
+    },
+    /**
+     * Mask the personal information found by the detectors in the output buffer, or only in the
+     * string values at the JSON pointers in `paths`
+     */
+    Redact {
+        /**
+         * The built-in detectors to apply, all of them are applied when neither `detectors` nor
+         * `patterns` are given
+         */
+        #[serde(default)]
+        detectors: Vec<Detector>,
+        /**
+         * Additional named regular expressions, of which only the `secret` capture group is
+         * replaced if it has one
+         */
+        #[serde(default)]
+        patterns: HashMap<String, String>,
+        #[serde(default)]
+        paths: Vec<String>,
+        #[serde(default = "redact_mask_default")]
+        mask: String,
+        /**
+         * Replace matches with a hash keyed by this secret rather than the mask, so that redacted
+         * values can still be correlated
+         */
+        #[serde(default = "default_none")]
+        hash_key: Option<String>,
     },
     Replace {
         template: String,
     },
+    /**
+     * Continue with the following actions for only a fraction of the messages, discarding the rest
+     */
+    Sample {
+        /**
+         * The percentage of messages to keep
+         */
+        #[serde(default = "default_none")]
+        percent: Option<f64>,
+        /**
+         * Keep one in every `every` messages
+         */
+        #[serde(default = "default_none")]
+        every: Option<u64>,
+        /**
+         * An optional template whose rendered value decides whether a message is kept, so that
+         * the messages sharing a key are either all kept or all discarded
+         */
+        #[serde(default = "default_none")]
+        key: Option<String>,
+    },
+    /**
+     * Set the field at the JSON pointer, substituting variables into the string values as with a
+     * typed merge
+     */
+    Set {
+        path: String,
+        value: Value,
+        #[serde(default = "default_none")]
+        coerce: Option<Coercion>,
+    },
     Stop,
 }
 
 impl Action {
     fn populate_caches(&mut self) {
-        if let Action::Merge { json, json_str } = self {
+        if let Action::Merge { json, json_str, .. } = self {
             *json_str =
                 Some(serde_json::to_string(json).expect("Failed to serialize Merge action"));
         }
     }
 }
 
+/**
+ * A Condition is either a single `field` with a matcher, or a compound of other conditions with
+ * `all`, `any`, or `not`
+ */
+#[derive(Debug, Default, Deserialize)]
+pub struct Condition {
+    #[serde(default = "default_none")]
+    pub field: Option<Field>,
+    #[serde(with = "serde_regex", default = "default_none")]
+    pub regex: Option<regex::Regex>,
+    #[serde(default = "default_none")]
+    pub jmespath: Option<String>,
+    /**
+     * Compare the field against a value, e.g. `<= warning`
+     */
+    #[serde(default = "default_none")]
+    pub compare: Option<Comparison>,
+    /**
+     * The `id.param` path of the structured data parameter to match for the `sd` field
+     */
+    #[serde(default = "default_none")]
+    pub path: Option<String>,
+    /**
+     * Matches when every one of the conditions match
+     */
+    #[serde(default)]
+    pub all: Vec<Condition>,
+    /**
+     * Matches when at least one of the conditions match
+     */
+    #[serde(default)]
+    pub any: Vec<Condition>,
+    /**
+     * Matches when the condition does not match
+     */
+    #[serde(default = "default_none")]
+    pub not: Option<Box<Condition>>,
+}
+
+impl Condition {
+    /**
+     * Return all the conditions in this tree, including this one
+     */
+    pub fn flatten(&self) -> Vec<&Condition> {
+        let mut conditions = vec![self];
+        for condition in self.all.iter().chain(self.any.iter()) {
+            conditions.append(&mut condition.flatten());
+        }
+        if let Some(condition) = &self.not {
+            conditions.append(&mut condition.flatten());
+        }
+        conditions
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        let join = |conditions: &Vec<Condition>| {
+            conditions
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        if !self.all.is_empty() {
+            write!(f, "All({})", join(&self.all))
+        } else if !self.any.is_empty() {
+            write!(f, "Any({})", join(&self.any))
+        } else if let Some(condition) = &self.not {
+            write!(f, "Not({})", condition)
+        } else if let Some(regex) = &self.regex {
+            write!(f, "Regex: {}", regex)
+        } else if let Some(comparison) = &self.compare {
+            write!(
+                f,
+                "Compare: {:?} {}",
+                comparison.operator, comparison.operand
+            )
+        } else if let Some(jmespath) = &self.jmespath {
+            write!(f, "JMESPath: {}", jmespath)
+        } else {
+            write!(f, "Nothing")
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Rule {
     #[serde(skip_serializing, skip_deserializing, default = "default_uuid")]
     pub uuid: Uuid,
-    pub field: Field,
-    pub actions: Vec<Action>,
-    #[serde(with = "serde_regex", default = "default_none")]
-    pub regex: Option<regex::Regex>,
+    /**
+     * The name of the rule in metrics, which defaults to its position in the rules
+     */
     #[serde(default = "default_none")]
-    pub jmespath: Option<String>,
+    pub name: Option<String>,
+    pub actions: Vec<Action>,
+    /**
+     * Parse the message as JSON, exposing it to templates as the `json` variable
+     */
+    #[serde(default)]
+    pub parse_json: bool,
+    /**
+     * Named JMESPath expressions whose results, when the rule matches, are exposed to templates
+     * as variables of the same name
+     */
+    #[serde(default)]
+    pub extract: HashMap<String, String>,
+    #[serde(flatten)]
+    pub condition: Condition,
 }
 
 impl Rule {
@@ -109,15 +473,11 @@ impl Rule {
 }
 impl std::fmt::Display for Rule {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        if let Some(regex) = &self.regex {
-            write!(f, "Regex: {}", regex)
-        } else {
-            write!(f, "JMESPath: {}", self.jmespath.as_ref().unwrap())
-        }
+        write!(f, "{}", self.condition)
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(untagged)]
 pub enum TlsType {
     None,
@@ -139,12 +499,156 @@ impl Default for TlsType {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/**
+ * The types which the values of a typed merge can be converted to
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Coercion {
+    Int,
+    Float,
+    Bool,
+    /**
+     * Parse the value as JSON, allowing arrays and objects to be merged
+     */
+    Json,
+}
+
+/**
+ * The built-in detectors of personal information for the redact action
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Detector {
+    Email,
+    Ipv4,
+    Ipv6,
+    CreditCard,
+    BearerToken,
+}
+
+/**
+ * The hashing strategy used to select a partition from the key of a Kafka message
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Partitioner {
+    /**
+     * Compatible with the default partitioner of the Java client
+     */
+    Murmur2,
+    /**
+     * Compatible with the default partitioner of librdkafka
+     */
+    ConsistentRandom,
+}
+
+/**
+ * The transport protocol a listener should receive syslog messages over
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Default for Protocol {
+    fn default() -> Protocol {
+        Protocol::Tcp
+    }
+}
+
+/**
+ * The method used for delimiting syslog messages sent over a TCP or TLS stream, as described in
+ * RFC 6587
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Framing {
+    /**
+     * Detect octet-counting or non-transparent-framing on a message-by-message basis
+     */
+    Auto,
+    OctetCounted,
+    NonTransparent,
+}
+
+impl Default for Framing {
+    fn default() -> Framing {
+        Framing::Auto
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct Listen {
     pub address: String,
     pub port: u64,
     #[serde(default)]
     pub tls: TlsType,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub framing: Framing,
+    /**
+     * The largest message which will be accepted from a TCP or TLS stream, connections sending
+     * anything larger are closed
+     */
+    #[serde(default = "max_frame_size_default")]
+    pub max_frame_size: usize,
+    /**
+     * The largest UDP datagram which will be accepted, anything larger will be truncated
+     */
+    #[serde(default = "datagram_size_default")]
+    pub datagram_size: usize,
+    /**
+     * Join the lines of messages which span several syslog lines, such as stack traces, before
+     * the rules are applied
+     */
+    #[serde(default = "default_none")]
+    pub multiline: Option<Multiline>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Multiline {
+    /**
+     * Lines matching `start` always begin a new message, and when there is no `continuation`
+     * every other line continues the previous message
+     */
+    #[serde(with = "serde_regex", default = "default_none")]
+    pub start: Option<regex::Regex>,
+    /**
+     * Lines matching `continuation` continue the previous message from the same hostname,
+     * appname, and procid
+     */
+    #[serde(with = "serde_regex", default = "default_none")]
+    pub continuation: Option<regex::Regex>,
+    /**
+     * The most lines joined into a single message
+     */
+    #[serde(default = "multiline_max_lines_default")]
+    pub max_lines: usize,
+    /**
+     * How long to wait for another line before the message is considered complete
+     */
+    #[serde(default = "multiline_timeout_ms_default")]
+    pub timeout_ms: u64,
+}
+
+impl Multiline {
+    /**
+     * Ensure that lines can be joined at all, since without either regular expression every line
+     * would be a message of its own
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start.is_none() && self.continuation.is_none() {
+            return Err("A `multiline` must have a `start` or `continuation` pattern".to_string());
+        }
+        if self.max_lines == 0 {
+            return Err("The `max_lines` of a `multiline` must be at least 1".to_string());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -155,6 +659,23 @@ pub struct Kafka {
     pub timeout_ms: Duration,
     pub conf: HashMap<String, String>,
     pub topic: String,
+    /**
+     * When configured, messages which cannot be delivered to Kafka will be spooled to disk
+     */
+    pub spool: Option<Spool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Spool {
+    /**
+     * The directory where spooled messages will be written
+     */
+    pub path: std::path::PathBuf,
+    /**
+     * The maximum number of bytes which can be spooled to disk
+     */
+    #[serde(default = "spool_max_bytes_default")]
+    pub max_bytes: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -171,9 +692,128 @@ pub struct Status {
 #[derive(Debug, Deserialize)]
 pub struct Global {
     pub kafka: Kafka,
-    pub listen: Listen,
+    /**
+     * Every listener will be served simultaneously, sharing the same Kafka producer
+     */
+    pub listen: Vec<Listen>,
     pub metrics: Metrics,
     pub status: Option<Status>,
+    /**
+     * Named outputs which rules can send messages to with the output action
+     */
+    #[serde(default)]
+    pub outputs: HashMap<String, Output>,
+}
+
+/**
+ * The destinations which messages can be sent to by the output action
+ */
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Output {
+    Stdout,
+    Kafka {
+        topic: String,
+        #[serde(default = "default_none")]
+        key: Option<String>,
+    },
+    File {
+        /**
+         * A template for the path of the file the message should be written to
+         */
+        path: String,
+        /**
+         * Rotate the file once it would grow larger than this many bytes
+         */
+        #[serde(default = "default_none")]
+        rotate_bytes: Option<u64>,
+        /**
+         * Rotate the file once it is this many seconds old
+         */
+        #[serde(default = "default_none")]
+        rotate_secs: Option<u64>,
+        /**
+         * Compress rotated files with gzip
+         */
+        #[serde(default)]
+        gzip: bool,
+    },
+    Syslog {
+        /**
+         * The `host:port` of the syslog server to relay messages to
+         */
+        address: String,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default)]
+        tls: bool,
+        #[serde(default)]
+        format: SyslogFormat,
+        /**
+         * The framing to use over TCP, ignored for UDP
+         */
+        #[serde(default)]
+        framing: Framing,
+    },
+    Http(Http),
+}
+
+/**
+ * The configuration of the http output
+ */
+#[derive(Debug, Deserialize)]
+pub struct Http {
+    pub url: String,
+    #[serde(default = "http_method_default")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default = "default_none")]
+    pub auth: Option<HttpAuth>,
+    /**
+     * The maximum number of messages to send in a single request
+     */
+    #[serde(default = "http_batch_size_default")]
+    pub batch_size: usize,
+    /**
+     * The maximum number of milliseconds to wait for a batch to fill up before sending it
+     */
+    #[serde(default = "http_batch_ms_default")]
+    pub batch_ms: u64,
+    /**
+     * The number of times to retry a batch which failed due to a server or connection error
+     */
+    #[serde(default = "http_retries_default")]
+    pub retries: u32,
+    /**
+     * The maximum number of milliseconds to wait for a request, including connecting, before it
+     * is considered failed
+     */
+    #[serde(default = "http_timeout_ms_default")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum HttpAuth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+/**
+ * The format of the syslog messages relayed by the syslog output
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SyslogFormat {
+    Rfc5424,
+    Rfc3164,
+}
+
+impl Default for SyslogFormat {
+    fn default() -> SyslogFormat {
+        SyslogFormat::Rfc5424
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -183,6 +823,18 @@ pub struct Settings {
 }
 
 impl Settings {
+    /**
+     * Check the parts of the configuration which cannot be checked while deserializing it
+     */
+    fn validate(&self) -> Result<(), String> {
+        for listen in self.global.listen.iter() {
+            if let Some(multiline) = &listen.multiline {
+                multiline.validate()?;
+            }
+        }
+        Ok(())
+    }
+
     /**
      * Populate any configuration caches which we want to us
      */
@@ -204,10 +856,68 @@ fn kafka_buffer_default() -> usize {
     1024
 }
 
+/**
+ * 64 KiB is far larger than any reasonable syslog message, while still small enough that a broken
+ * or malicious sender cannot exhaust our memory
+ */
+fn max_frame_size_default() -> usize {
+    65_536
+}
+
+/**
+ * Return the default maximum size for UDP datagrams, which mirrors the rsyslog default
+ */
+fn datagram_size_default() -> usize {
+    8192
+}
+
+/**
+ * Return the default size cap for the spool, 1GiB
+ */
+fn spool_max_bytes_default() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn http_method_default() -> String {
+    "POST".to_string()
+}
+
+fn http_batch_size_default() -> usize {
+    100
+}
+
+fn http_batch_ms_default() -> u64 {
+    1000
+}
+
+fn http_retries_default() -> u32 {
+    3
+}
+
+fn http_timeout_ms_default() -> u64 {
+    10_000
+}
+
 fn kafka_timeout_default() -> Duration {
     Duration::from_secs(30)
 }
 
+fn multiline_max_lines_default() -> usize {
+    500
+}
+
+fn multiline_timeout_ms_default() -> u64 {
+    1000
+}
+
+fn dedupe_summary_default() -> String {
+    "last message repeated {{repeats}} times".to_string()
+}
+
+fn redact_mask_default() -> String {
+    "[REDACTED]".to_string()
+}
+
 fn default_none<T>() -> Option<T> {
     None
 }
@@ -222,7 +932,18 @@ mod tests {
 
     #[test]
     fn test_load_example_config() {
-        load("hotdog.yml");
+        let settings = load("hotdog.yml");
+        assert_eq!(2, settings.global.listen.len());
+        assert_eq!(TlsType::None, settings.global.listen[0].tls);
+        assert_ne!(TlsType::None, settings.global.listen[1].tls);
+    }
+
+    #[test]
+    fn test_load_single_listener() {
+        let settings = load("test/configs/single-listener.yml");
+        assert_eq!(1, settings.global.listen.len());
+        assert_eq!(1514, settings.global.listen[0].port);
+        assert_eq!(TlsType::None, settings.global.listen[0].tls);
     }
 
     #[test]
@@ -230,7 +951,7 @@ mod tests {
         let settings = load("test/configs/single-rule-with-merge.yml");
         assert_eq!(settings.rules.len(), 1);
         match &settings.rules[0].actions[0] {
-            Action::Merge { json: _, json_str } => {
+            Action::Merge { json_str, .. } => {
                 assert!(json_str.is_some());
             }
             _ => {
@@ -239,11 +960,212 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_try_load_invalid_extension() {
+        assert!(try_load("README.adoc").is_err());
+    }
+
     #[test]
     fn test_default_tls() {
         assert_eq!(TlsType::None, TlsType::default());
     }
 
+    #[test]
+    fn test_default_protocol() {
+        assert_eq!(Protocol::Tcp, Protocol::default());
+    }
+
+    #[test]
+    fn test_default_framing() {
+        assert_eq!(Framing::Auto, Framing::default());
+    }
+
+    #[test]
+    fn test_load_octet_counted_config() {
+        let settings = load("test/configs/octet-counted-listener.yml");
+        assert_eq!(Framing::OctetCounted, settings.global.listen[0].framing);
+        assert_eq!(
+            max_frame_size_default(),
+            settings.global.listen[0].max_frame_size
+        );
+    }
+
+    #[test]
+    fn test_load_udp_config() {
+        let settings = load("test/configs/udp-listener.yml");
+        assert_eq!(Protocol::Udp, settings.global.listen[0].protocol);
+        assert_eq!(1024, settings.global.listen[0].datagram_size);
+    }
+
+    #[test]
+    fn test_load_multiline_config() {
+        let settings = load("test/configs/listen-with-multiline.yml");
+        let multiline = settings.global.listen[0]
+            .multiline
+            .as_ref()
+            .expect("No multiline configured");
+        assert!(multiline.start.is_none());
+        assert_eq!(10, multiline.max_lines);
+    }
+
+    #[test]
+    fn test_load_multiline_without_patterns() {
+        assert!(try_load("test/configs/listen-with-invalid-multiline.yml").is_err());
+    }
+
+    #[test]
+    fn test_validate_multiline_max_lines() {
+        let mut multiline = Multiline {
+            start: None,
+            continuation: Some(regex::Regex::new("^ ").expect("Invalid regex")),
+            max_lines: 1,
+            timeout_ms: 1000,
+        };
+        assert!(multiline.validate().is_ok());
+
+        multiline.max_lines = 0;
+        assert!(multiline.validate().is_err());
+    }
+
+    #[test]
+    fn test_load_spool_config() {
+        let settings = load("test/configs/kafka-with-spool.yml");
+        let spool = settings.global.kafka.spool.expect("No spool configured");
+        assert_eq!(std::path::PathBuf::from("/var/spool/hotdog"), spool.path);
+        assert_eq!(spool_max_bytes_default(), spool.max_bytes);
+    }
+
+    #[test]
+    fn test_load_outputs() {
+        let settings = load("test/configs/single-rule-with-output.yml");
+        assert_eq!(2, settings.global.outputs.len());
+        match &settings.global.outputs["audit"] {
+            Output::Kafka { topic, key } => {
+                assert_eq!("audit", topic);
+                assert_eq!(&Some("{{hostname}}".to_string()), key);
+            }
+            _ => panic!("The audit output should have been Kafka"),
+        }
+    }
+
+    #[test]
+    fn test_load_file_output() {
+        let settings = load("test/configs/file-output.yml");
+        match &settings.global.outputs["archive"] {
+            Output::File {
+                path,
+                rotate_bytes,
+                rotate_secs,
+                gzip,
+            } => {
+                assert_eq!("/var/log/hotdog/{{appname}}/{{date}}.log", path);
+                assert_eq!(&Some(1048576), rotate_bytes);
+                assert_eq!(&None, rotate_secs);
+                assert!(gzip);
+            }
+            _ => panic!("The archive output should have been a file"),
+        }
+    }
+
+    #[test]
+    fn test_load_syslog_output() {
+        let settings = load("test/configs/syslog-output.yml");
+        match &settings.global.outputs["siem"] {
+            Output::Syslog {
+                address,
+                protocol,
+                tls,
+                format,
+                framing,
+            } => {
+                assert_eq!("siem.example.com:6514", address);
+                assert_eq!(&Protocol::Tcp, protocol);
+                assert!(tls);
+                assert_eq!(&SyslogFormat::Rfc3164, format);
+                assert_eq!(&Framing::Auto, framing);
+            }
+            _ => panic!("The siem output should have been syslog"),
+        }
+    }
+
+    #[test]
+    fn test_load_http_output() {
+        let settings = load("test/configs/http-output.yml");
+        match &settings.global.outputs["collector"] {
+            Output::Http(http) => {
+                assert_eq!("http://localhost:9200/_bulk", http.url);
+                assert_eq!("POST", http.method);
+                assert_eq!(
+                    Some(HttpAuth::Basic {
+                        username: "hotdog".to_string(),
+                        password: "sekrit".to_string(),
+                    }),
+                    http.auth
+                );
+                assert_eq!(500, http.batch_size);
+                assert_eq!(http_batch_ms_default(), http.batch_ms);
+                assert_eq!(http_retries_default(), http.retries);
+                assert_eq!(http_timeout_ms_default(), http.timeout_ms);
+            }
+            _ => panic!("The collector output should have been http"),
+        }
+    }
+
+    #[test]
+    fn test_parse_comparison() {
+        assert_eq!(
+            Ok(Comparison {
+                operator: Operator::Lte,
+                operand: "warning".to_string()
+            }),
+            "<= warning".parse::<Comparison>()
+        );
+        assert_eq!(
+            Ok(Comparison {
+                operator: Operator::Gt,
+                operand: "1000".to_string()
+            }),
+            ">1000".parse::<Comparison>()
+        );
+        assert!("warning".parse::<Comparison>().is_err());
+        assert!("<=".parse::<Comparison>().is_err());
+    }
+
+    #[test]
+    fn test_load_sd_and_compare_rules() {
+        let settings = load("test/configs/rules-with-sd-and-compare.yml");
+        assert_eq!(
+            Some("origin.ip".to_string()),
+            settings.rules[0].condition.path
+        );
+        assert_eq!(
+            Some(Comparison {
+                operator: Operator::Lte,
+                operand: "warning".to_string()
+            }),
+            settings.rules[1].condition.compare
+        );
+    }
+
+    #[test]
+    fn test_load_compound_conditions() {
+        let settings = load("test/configs/rule-with-conditions.yml");
+        let condition = &settings.rules[0].condition;
+        assert_eq!(2, condition.all.len());
+        assert_eq!(2, condition.all[1].any.len());
+        assert!(condition.all[1].any[1].not.is_some());
+        assert_eq!(6, condition.flatten().len());
+    }
+
+    #[test]
+    fn test_load_parse_json() {
+        let settings = load("test/configs/single-rule-with-parse-json.yml");
+        assert!(settings.rules[0].parse_json);
+
+        let settings = load("test/configs/single-rule-with-merge.yml");
+        assert!(!settings.rules[0].parse_json);
+    }
+
     #[test]
     fn test_kafka_buffer_default() {
         assert_eq!(1024, kafka_buffer_default());
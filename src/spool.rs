@@ -0,0 +1,512 @@
+use crate::kafka::KafkaMessage;
+use crate::settings;
+use crate::status::{Statistic, Stats};
+/**
+ * The spool module implements an on-disk buffer for messages which cannot currently be delivered
+ * to Kafka.
+ *
+ * Messages are appended as JSON lines to numbered segment files inside the spool directory, and
+ * replayed a segment at a time, oldest first. A segment is only removed from disk once every message
+ * in it has been delivered, so a crash or failure during replay may result in messages being
+ * delivered more than once.
+ *
+ * Reading and writing the segments blocks, so it is done on blocking threads rather than the
+ * executor.
+ */
+use async_std::{
+    sync::{Arc, Sender},
+    task,
+};
+use log::*;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/**
+ * Segments are rotated once they have grown past this size, which bounds how many messages are
+ * held in memory during replay
+ */
+const SEGMENT_BYTES: u64 = 1024 * 1024;
+
+/**
+ * A Segment is a batch of spooled messages which have been taken off the spool for replay
+ */
+pub struct Segment {
+    id: u64,
+    bytes: u64,
+    count: u64,
+    pub messages: Vec<KafkaMessage>,
+}
+
+pub struct Spool {
+    inner: Arc<Mutex<SpoolInner>>,
+    max_bytes: u64,
+    stats: Sender<Statistic>,
+}
+
+struct SpoolInner {
+    dir: PathBuf,
+    /**
+     * Identifiers of the segments on disk waiting to be replayed, oldest first
+     */
+    segments: VecDeque<u64>,
+    next_id: u64,
+    /**
+     * The newest segment which messages are being appended to
+     */
+    writer: Option<File>,
+    writer_bytes: u64,
+    /**
+     * Totals include segments which have been taken for replay but not yet finished
+     */
+    messages: u64,
+    bytes: u64,
+}
+
+impl SpoolInner {
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.spool", id))
+    }
+
+    /**
+     * Start appending to a brand new segment
+     */
+    fn rotate(&mut self) -> io::Result<()> {
+        let id = self.next_id;
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(self.segment_path(id))?;
+
+        self.next_id += 1;
+        self.segments.push_back(id);
+        self.writer = Some(file);
+        self.writer_bytes = 0;
+        Ok(())
+    }
+
+    /**
+     * Append the serialized message, returning false if it would not fit within `max_bytes`
+     */
+    fn append(&mut self, line: &str, bytes: u64, max_bytes: u64) -> io::Result<bool> {
+        if self.bytes + bytes > max_bytes {
+            return Ok(false);
+        }
+
+        if self.writer.is_none() || self.writer_bytes >= SEGMENT_BYTES {
+            self.rotate()?;
+        }
+
+        if let Some(writer) = self.writer.as_mut() {
+            writeln!(writer, "{}", line)?;
+        }
+        self.writer_bytes += bytes;
+        self.messages += 1;
+        self.bytes += bytes;
+        Ok(true)
+    }
+
+    fn read_oldest(&mut self) -> io::Result<Option<Segment>> {
+        let id = match self.segments.pop_front() {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        /*
+         * If the oldest segment is still being appended to, subsequent messages must go into a
+         * new segment
+         */
+        if self.segments.is_empty() {
+            self.writer = None;
+        }
+
+        let contents = match std::fs::read_to_string(self.segment_path(id)) {
+            Ok(contents) => contents,
+            Err(e) => {
+                /*
+                 * Leave the segment to be read again rather than losing track of it
+                 */
+                self.segments.push_front(id);
+                return Err(e);
+            }
+        };
+        let mut messages = vec![];
+
+        for line in contents.lines() {
+            match serde_json::from_str(line) {
+                Ok(kmsg) => messages.push(kmsg),
+                Err(e) => error!("Discarding a corrupted message in the spool: {:?}", e),
+            }
+        }
+
+        Ok(Some(Segment {
+            id,
+            bytes: contents.len() as u64,
+            count: contents.lines().count() as u64,
+            messages,
+        }))
+    }
+
+    /**
+     * Replace the contents of the segment, without ever leaving it partially written
+     */
+    fn rewrite(&self, id: u64, contents: &str) -> io::Result<()> {
+        let path = self.segment_path(id);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, contents)?;
+        std::fs::rename(&temporary, &path)
+    }
+}
+
+impl Spool {
+    /**
+     * Open the spool directory, creating it if necessary, and pick up any segments left behind by
+     * a previous run of hotdog
+     */
+    pub fn open(conf: &settings::Spool, stats: Sender<Statistic>) -> io::Result<Spool> {
+        std::fs::create_dir_all(&conf.path)?;
+
+        let mut ids = vec![];
+        for entry in std::fs::read_dir(&conf.path)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "spool") {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+
+        let mut inner = SpoolInner {
+            dir: conf.path.clone(),
+            segments: VecDeque::new(),
+            next_id: ids.last().map_or(0, |id| id + 1),
+            writer: None,
+            writer_bytes: 0,
+            messages: 0,
+            bytes: 0,
+        };
+
+        for id in ids {
+            let contents = std::fs::read_to_string(inner.segment_path(id))?;
+            inner.messages += contents.lines().count() as u64;
+            inner.bytes += contents.len() as u64;
+            inner.segments.push_back(id);
+        }
+
+        if inner.messages > 0 {
+            info!(
+                "Found {} spooled messages in {}",
+                inner.messages,
+                conf.path.display()
+            );
+        }
+
+        Ok(Spool {
+            inner: Arc::new(Mutex::new(inner)),
+            max_bytes: conf.max_bytes,
+            stats,
+        })
+    }
+
+    /**
+     * Returns true when there are no messages waiting to be replayed
+     */
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().messages == 0
+    }
+
+    /**
+     * Report the current depth of the spool, typically only needed at startup
+     */
+    pub async fn report(&self) {
+        let (messages, bytes) = {
+            let inner = self.inner.lock();
+            (inner.messages, inner.bytes)
+        };
+        self.stats.send((Stats::SpoolDepth, messages as i64)).await;
+        self.stats.send((Stats::SpoolBytes, bytes as i64)).await;
+    }
+
+    /**
+     * Append the message to the spool, returning false if it could not be spooled
+     */
+    pub async fn push(&self, kmsg: &KafkaMessage) -> bool {
+        let line = match serde_json::to_string(kmsg) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize a message for the spool: {:?}", e);
+                return false;
+            }
+        };
+        let bytes = line.len() as u64 + 1;
+        let inner = self.inner.clone();
+        let max_bytes = self.max_bytes;
+        let result =
+            task::spawn_blocking(move || inner.lock().append(&line, bytes, max_bytes)).await;
+
+        match result {
+            Ok(true) => {
+                self.stats.send((Stats::SpoolDepth, 1)).await;
+                self.stats.send((Stats::SpoolBytes, bytes as i64)).await;
+                true
+            }
+            Ok(false) => {
+                warn!("The spool is full, unable to spool message");
+                self.stats.send((Stats::SpoolFullError, 1)).await;
+                false
+            }
+            Err(e) => {
+                error!("Failed to write to the spool: {:?}", e);
+                self.stats.send((Stats::SpoolIoError, 1)).await;
+                false
+            }
+        }
+    }
+
+    /**
+     * Take the oldest segment off of the spool so that it can be replayed.
+     *
+     * The segment will remain on disk, and count towards the spool's depth, until it has been
+     * passed to finish() or requeue()
+     */
+    pub async fn take_segment(&self) -> Option<Segment> {
+        let inner = self.inner.clone();
+
+        match task::spawn_blocking(move || inner.lock().read_oldest()).await {
+            Ok(segment) => segment,
+            Err(e) => {
+                error!("Failed to read from the spool: {:?}", e);
+                self.stats.send((Stats::SpoolIoError, 1)).await;
+                None
+            }
+        }
+    }
+
+    /**
+     * Remove a segment from the spool once every message in it has been delivered
+     */
+    pub async fn finish(&self, segment: Segment) {
+        let inner = self.inner.clone();
+        let (id, count, bytes) = (segment.id, segment.count, segment.bytes);
+
+        let result = task::spawn_blocking(move || {
+            let mut inner = inner.lock();
+            inner.messages -= count;
+            inner.bytes -= bytes;
+            std::fs::remove_file(inner.segment_path(id))
+        })
+        .await;
+
+        if let Err(e) = result {
+            error!("Failed to remove a replayed spool segment: {:?}", e);
+            self.stats.send((Stats::SpoolIoError, 1)).await;
+        }
+
+        self.stats
+            .send((Stats::SpoolDepth, -(segment.count as i64)))
+            .await;
+        self.stats
+            .send((Stats::SpoolBytes, -(segment.bytes as i64)))
+            .await;
+    }
+
+    /**
+     * Return the messages of the segment from `delivered` onwards, which have not been delivered,
+     * to the front of the spool so that they are replayed before anything spooled since
+     */
+    pub async fn requeue(&self, segment: Segment, delivered: usize) {
+        let mut contents = String::new();
+        let mut count = 0;
+        for kmsg in segment.messages.iter().skip(delivered) {
+            if let Ok(line) = serde_json::to_string(kmsg) {
+                contents.push_str(&line);
+                contents.push('\n');
+                count += 1;
+            }
+        }
+        let bytes = contents.len() as u64;
+
+        let inner = self.inner.clone();
+        let id = segment.id;
+        let result = task::spawn_blocking(move || {
+            let mut inner = inner.lock();
+            inner.segments.push_front(id);
+            inner.rewrite(id, &contents)
+        })
+        .await;
+
+        /*
+         * If the segment couldn't be rewritten it is left as it was, and will be replayed in full
+         */
+        if let Err(e) = result {
+            error!("Failed to rewrite a partially replayed segment: {:?}", e);
+            self.stats.send((Stats::SpoolIoError, 1)).await;
+            return;
+        }
+
+        {
+            let mut inner = self.inner.lock();
+            inner.messages -= segment.count - count;
+            inner.bytes -= segment.bytes - bytes;
+        }
+        self.stats
+            .send((Stats::SpoolDepth, -((segment.count - count) as i64)))
+            .await;
+        self.stats
+            .send((Stats::SpoolBytes, -((segment.bytes - bytes) as i64)))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::sync::channel;
+    use async_std::task;
+
+    /**
+     * Generate spool settings pointing to a unique temporary directory
+     */
+    fn spool_settings(max_bytes: u64) -> settings::Spool {
+        settings::Spool {
+            path: std::env::temp_dir().join(format!("hotdog-spool-{}", uuid::Uuid::new_v4())),
+            max_bytes,
+        }
+    }
+
+    #[test]
+    fn test_push_and_replay_in_order() {
+        task::block_on(async {
+            let (stats, _stats_receiver) = channel(100);
+            let conf = spool_settings(1024);
+            let spool = Spool::open(&conf, stats).expect("Failed to open the spool");
+            assert!(spool.is_empty());
+
+            assert!(spool.push(&KafkaMessage::new("a".into(), "1".into())).await);
+            assert!(spool.push(&KafkaMessage::new("b".into(), "2".into())).await);
+            assert!(!spool.is_empty());
+
+            let segment = spool.take_segment().await.expect("No segment spooled");
+            assert_eq!(
+                vec![
+                    KafkaMessage::new("a".into(), "1".into()),
+                    KafkaMessage::new("b".into(), "2".into()),
+                ],
+                segment.messages
+            );
+            /* Messages being replayed still count until the segment has been finished */
+            assert!(!spool.is_empty());
+
+            spool.finish(segment).await;
+            assert!(spool.is_empty());
+            assert!(spool.take_segment().await.is_none());
+
+            std::fs::remove_dir_all(&conf.path).expect("Failed to clean up");
+        });
+    }
+
+    /**
+     * Undelivered messages should be replayed again before those spooled since
+     */
+    #[test]
+    fn test_requeue() {
+        task::block_on(async {
+            let (stats, _stats_receiver) = channel(100);
+            let conf = spool_settings(1024);
+            let spool = Spool::open(&conf, stats).expect("Failed to open the spool");
+
+            for (topic, msg) in &[("a", "1"), ("b", "2"), ("c", "3")] {
+                assert!(
+                    spool
+                        .push(&KafkaMessage::new(topic.to_string(), msg.to_string()))
+                        .await
+                );
+            }
+            let segment = spool.take_segment().await.expect("No segment spooled");
+            assert!(spool.push(&KafkaMessage::new("d".into(), "4".into())).await);
+
+            spool.requeue(segment, 1).await;
+
+            let retried = spool
+                .take_segment()
+                .await
+                .expect("The segment was not requeued");
+            assert_eq!(
+                vec![
+                    KafkaMessage::new("b".into(), "2".into()),
+                    KafkaMessage::new("c".into(), "3".into()),
+                ],
+                retried.messages
+            );
+            spool.finish(retried).await;
+
+            let newer = spool.take_segment().await.expect("No segment spooled");
+            assert_eq!(
+                vec![KafkaMessage::new("d".into(), "4".into())],
+                newer.messages
+            );
+            spool.finish(newer).await;
+            assert!(spool.is_empty());
+
+            std::fs::remove_dir_all(&conf.path).expect("Failed to clean up");
+        });
+    }
+
+    #[test]
+    fn test_push_when_full() {
+        task::block_on(async {
+            let (stats, _stats_receiver) = channel(100);
+            let conf = spool_settings(10);
+            let spool = Spool::open(&conf, stats).expect("Failed to open the spool");
+
+            assert!(
+                !spool
+                    .push(&KafkaMessage::new("topic".into(), "too large".into()))
+                    .await
+            );
+            assert!(spool.is_empty());
+
+            std::fs::remove_dir_all(&conf.path).expect("Failed to clean up");
+        });
+    }
+
+    #[test]
+    fn test_reopen_existing_spool() {
+        task::block_on(async {
+            let (stats, _stats_receiver) = channel(100);
+            let conf = spool_settings(1024);
+            {
+                let spool = Spool::open(&conf, stats.clone()).expect("Failed to open the spool");
+                assert!(spool.push(&KafkaMessage::new("a".into(), "1".into())).await);
+            }
+
+            let spool = Spool::open(&conf, stats).expect("Failed to reopen the spool");
+            assert!(!spool.is_empty());
+            assert!(spool.push(&KafkaMessage::new("b".into(), "2".into())).await);
+
+            let first = spool.take_segment().await.expect("No segment spooled");
+            assert_eq!(
+                vec![KafkaMessage::new("a".into(), "1".into())],
+                first.messages
+            );
+            spool.finish(first).await;
+
+            let second = spool.take_segment().await.expect("No segment spooled");
+            assert_eq!(
+                vec![KafkaMessage::new("b".into(), "2".into())],
+                second.messages
+            );
+            spool.finish(second).await;
+            assert!(spool.is_empty());
+
+            std::fs::remove_dir_all(&conf.path).expect("Failed to clean up");
+        });
+    }
+}
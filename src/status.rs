@@ -1,3 +1,4 @@
+use crate::reload::ReloadableSettings;
 /**
  * This module contains the necessary code to launch the internal status HTTP
  * server when so configured by the administrator
@@ -5,6 +6,7 @@
  * The status module is also responsible for dispatching _all_ statsd metrics.
  */
 use async_std::sync::{channel, Arc, Receiver, Sender};
+use async_std::task;
 use dashmap::DashMap;
 use dipstick::{InputScope, StatsdScope};
 use log::*;
@@ -23,28 +25,62 @@ struct HealthResponse {
     stats: HashMap<String, i64>,
 }
 
+/**
+ * StatusState carries everything the status server's endpoints need access to
+ */
+#[derive(Clone)]
+struct StatusState {
+    stats: Arc<StatsHandler>,
+    settings: Arc<ReloadableSettings>,
+}
+
 /**
  * Launch the status server
  */
 pub async fn status_server(
     listen_to: String,
     stats: Arc<StatsHandler>,
+    settings: Arc<ReloadableSettings>,
 ) -> Result<(), std::io::Error> {
-    let mut app = tide::with_state(stats);
+    let mut app = tide::with_state(StatusState { stats, settings });
     debug!("Starting the status server on: {}", listen_to);
 
     app.at("/")
         .get(|_| async move { Ok("hotdog status server") });
 
     app.at("/stats")
-        .get(|req: Request<Arc<StatsHandler>>| async move {
-            let health = req.state().healthcheck().await;
+        .get(|req: Request<StatusState>| async move {
+            let health = req.state().stats.healthcheck().await;
 
             let mut res = Response::new(StatusCode::Ok);
             res.set_body(Body::from_json(&health)?);
             Ok(res)
         });
 
+    /*
+     * Reload the configuration, equivalent to sending hotdog a SIGHUP
+     */
+    app.at("/reload")
+        .post(|req: Request<StatusState>| async move {
+            /*
+             * Reading the file and compiling the rules blocks, so it shouldn't hold up the
+             * executor
+             */
+            let settings = req.state().settings.clone();
+            match task::spawn_blocking(move || settings.reload()).await {
+                Ok(_) => Ok(Response::new(StatusCode::Ok)),
+                Err(e) => {
+                    error!(
+                        "Failed to reload, keeping the previous configuration: {:?}",
+                        e
+                    );
+                    let mut res = Response::new(StatusCode::UnprocessableEntity);
+                    res.set_body(format!("Failed to reload the configuration: {:?}", e));
+                    Ok(res)
+                }
+            }
+        });
+
     app.listen(listen_to).await?;
     Ok(())
 }
@@ -85,7 +121,7 @@ impl StatsHandler {
                 trace!("Received stat to record: {} - {}", stat, count);
 
                 match stat {
-                    Stats::ConnectionCount => {
+                    Stats::ConnectionCount | Stats::SpoolDepth | Stats::SpoolBytes => {
                         self.handle_gauge(stat, count).await;
                     }
                     Stats::KafkaMsgSent => {
@@ -119,6 +155,33 @@ impl StatsHandler {
      */
     async fn handle_counter(&self, stat: Stats, count: i64) {
         let key = &stat.to_string();
+
+        /* Handle special case enums which have more data associated */
+        let subkey = match &stat {
+            Stats::KafkaMsgSubmitted { topic } => Some(topic),
+            Stats::KafkaMsgErrored { errcode } => Some(errcode),
+            Stats::OutputSent { name }
+            | Stats::OutputErrored { name }
+            | Stats::HttpMsgSent { name }
+            | Stats::HttpMsgFailed { name } => Some(name),
+            Stats::Deduplicated { rule }
+            | Stats::Dropped { rule }
+            | Stats::SampledOut { rule }
+            | Stats::RateLimited { rule } => Some(rule),
+            Stats::Redacted { detector } => Some(detector),
+            _ => None,
+        };
+
+        if let Some(subkey) = subkey {
+            self.increment(&format!("{}.{}", key, subkey), count);
+        }
+        self.increment(key, count);
+    }
+
+    /**
+     * Add the count to the counter with the given key, both internally and in statsd
+     */
+    fn increment(&self, key: &str, count: i64) {
         let mut new_count = 0;
 
         if let Some(counter) = self.values.get(key) {
@@ -129,22 +192,6 @@ impl StatsHandler {
         let sized_count: usize = count.try_into().expect("Could not convert to usize!");
 
         self.metrics.counter(key).count(sized_count);
-
-        /* Handle special case enums which have more data associated */
-        match &stat {
-            Stats::KafkaMsgSubmitted { topic } => {
-                let subkey = &*format!("{}.{}", key, topic);
-                self.metrics.counter(subkey).count(sized_count);
-                self.values.insert(subkey.to_string(), new_count);
-            }
-            Stats::KafkaMsgErrored { errcode } => {
-                let subkey = &*format!("{}.{}", key, errcode);
-                self.metrics.counter(subkey).count(sized_count);
-                self.values.insert(subkey.to_string(), new_count);
-            }
-            _ => {}
-        };
-
         self.values.insert(key.to_string(), new_count);
     }
 
@@ -185,10 +232,18 @@ pub enum Stats {
     /* Gauges */
     #[strum(serialize = "connections")]
     ConnectionCount,
+    #[strum(serialize = "spool.depth")]
+    SpoolDepth,
+    #[strum(serialize = "spool.bytes")]
+    SpoolBytes,
 
     /* Counters */
     #[strum(serialize = "lines")]
     LineReceived,
+    #[strum(serialize = "udp.received")]
+    DatagramReceived,
+    #[strum(serialize = "udp.truncated")]
+    DatagramTruncated,
     #[strum(serialize = "kafka.submitted")]
     KafkaMsgSubmitted { topic: String },
     #[strum(serialize = "kafka.producer.error")]
@@ -199,12 +254,44 @@ pub enum Stats {
     FullInternalQueueError,
     #[strum(serialize = "error.topic_parse_failed")]
     TopicParseFailed,
+    #[strum(serialize = "error.key_parse_failed")]
+    KeyParseFailed,
+    #[strum(serialize = "error.header_parse_failed")]
+    HeaderParseFailed,
+    #[strum(serialize = "error.partition_parse_failed")]
+    PartitionParseFailed,
     #[strum(serialize = "error.internal_push_failed")]
     InternalPushError,
     #[strum(serialize = "error.merge_of_invalid_json")]
     MergeInvalidJsonError,
     #[strum(serialize = "error.merge_target_not_json")]
     MergeTargetNotJsonError,
+    #[strum(serialize = "error.merge_path_invalid")]
+    MergePathError,
+    #[strum(serialize = "error.json_action_failed")]
+    JsonActionFailed,
+    #[strum(serialize = "deduplicated")]
+    Deduplicated { rule: String },
+    #[strum(serialize = "dropped")]
+    Dropped { rule: String },
+    #[strum(serialize = "sampled_out")]
+    SampledOut { rule: String },
+    #[strum(serialize = "rate_limited")]
+    RateLimited { rule: String },
+    #[strum(serialize = "redacted")]
+    Redacted { detector: String },
+    #[strum(serialize = "error.spool_full")]
+    SpoolFullError,
+    #[strum(serialize = "error.spool_io")]
+    SpoolIoError,
+    #[strum(serialize = "output.sent")]
+    OutputSent { name: String },
+    #[strum(serialize = "output.error")]
+    OutputErrored { name: String },
+    #[strum(serialize = "output.http.sent")]
+    HttpMsgSent { name: String },
+    #[strum(serialize = "output.http.failed")]
+    HttpMsgFailed { name: String },
 
     /* Timers */
     #[strum(serialize = "kafka.producer.sent")]
@@ -214,6 +301,37 @@ pub enum Stats {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use async_std::task;
+    use dipstick::{Input, Statsd};
+
+    fn stats_handler() -> StatsHandler {
+        let metrics = Statsd::send_to("localhost:8125")
+            .expect("Failed to create Statsd recorder")
+            .metrics();
+        StatsHandler::new(Arc::new(metrics))
+    }
+
+    /**
+     * Ensure that the counters for each rule only count their own messages
+     */
+    #[test]
+    fn test_counter_subkeys() {
+        task::block_on(async {
+            let stats = stats_handler();
+            let dropped = |rule: &str| Stats::Dropped {
+                rule: rule.to_string(),
+            };
+
+            stats.handle_counter(dropped("noisy"), 1).await;
+            stats.handle_counter(dropped("quiet"), 1).await;
+            stats.handle_counter(dropped("noisy"), 2).await;
+
+            let health = stats.healthcheck().await;
+            assert_eq!(Some(&4), health.stats.get("dropped"));
+            assert_eq!(Some(&3), health.stats.get("dropped.noisy"));
+            assert_eq!(Some(&1), health.stats.get("dropped.quiet"));
+        });
+    }
 
     #[test]
     fn test_sanity_check_strum_serialize() {